@@ -0,0 +1,691 @@
+//! Access control for the relay server.
+//!
+//! Only peers on the allowlist, or peers that redeemed a signed invite token, may reserve slots
+//! and open circuits. Every admitted peer is held to a [`Quota`], enforced through the relay's
+//! [`RateLimiter`](relay::RateLimiter) hooks:
+//!
+//! - at most [`Quota::circuits_per_hour`] circuits per hour,
+//! - at most [`Quota::bytes_per_hour`] relayed bytes per hour, where every circuit is charged
+//!   with the relay's full per-circuit byte limit up front,
+//! - reservations only within [`Quota::reservation_lifetime`] of the peer's first reservation.
+//!
+//! Allowlist files hold one peer per line, optionally followed by quota overrides:
+//!
+//! ```text
+//! # peer-id                                              overrides
+//! 12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN circuits=10 bytes=1048576 lifetime=86400
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{identity, relay, request_response, Multiaddr, PeerId, StreamProtocol};
+
+/// Protocol name of the invite redemption protocol.
+pub const INVITE_PROTOCOL: StreamProtocol = StreamProtocol::new("/hermes/relay-invite/1.0.0");
+
+/// Upper bound on the size of an invite request or response.
+const MAX_INVITE_SIZE: u64 = 4 * 1024;
+
+/// Period over which circuit and byte quotas are counted.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Limits applied to a single admitted peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub circuits_per_hour: u32,
+    pub bytes_per_hour: u64,
+    /// How long after its first reservation a peer may keep reserving. `None` is unlimited.
+    pub reservation_lifetime: Option<Duration>,
+}
+
+/// Who may use the relay, how much they used and how much they may use.
+#[derive(Debug)]
+pub struct AccessControl {
+    default_quota: Quota,
+    /// Byte limit of a single circuit, charged against [`Quota::bytes_per_hour`].
+    circuit_bytes: u64,
+    admitted: HashMap<PeerId, Admission>,
+    usage: HashMap<PeerId, Usage>,
+}
+
+#[derive(Debug)]
+struct Admission {
+    quota: Quota,
+    /// Set for peers admitted through an invite token.
+    expires_at: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+struct Usage {
+    window_start: Instant,
+    circuits: u32,
+    bytes: u64,
+    first_reservation: Option<Instant>,
+}
+
+impl AccessControl {
+    pub fn new(default_quota: Quota, circuit_bytes: u64) -> Self {
+        Self {
+            default_quota,
+            circuit_bytes,
+            admitted: Default::default(),
+            usage: Default::default(),
+        }
+    }
+
+    /// Admits `peer` permanently, with `quota` or the default quota.
+    pub fn allow(&mut self, peer: PeerId, quota: Option<Quota>) {
+        self.admitted.insert(
+            peer,
+            Admission {
+                quota: quota.unwrap_or(self.default_quota),
+                expires_at: None,
+            },
+        );
+    }
+
+    /// Admits every peer listed in the allowlist file at `path`.
+    pub fn load_allowlist(&mut self, path: &Path) -> io::Result<()> {
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (peer, quota) = self.parse_allowlist_line(line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {e}", path.display(), number + 1),
+                )
+            })?;
+            self.allow(peer, Some(quota));
+        }
+
+        Ok(())
+    }
+
+    fn parse_allowlist_line(&self, line: &str) -> Result<(PeerId, Quota), String> {
+        let mut fields = line.split_whitespace();
+        let peer = fields
+            .next()
+            .and_then(|peer| PeerId::from_str(peer).ok())
+            .ok_or("expected a peer id")?;
+
+        let mut quota = self.default_quota;
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {field}"))?;
+            let invalid = |_| format!("invalid value for {key}: {value}");
+            match key {
+                "circuits" => quota.circuits_per_hour = value.parse().map_err(invalid)?,
+                "bytes" => quota.bytes_per_hour = value.parse().map_err(invalid)?,
+                "lifetime" => {
+                    quota.reservation_lifetime =
+                        Some(Duration::from_secs(value.parse().map_err(invalid)?))
+                }
+                _ => return Err(format!("unknown quota {key}")),
+            }
+        }
+
+        Ok((peer, quota))
+    }
+
+    /// Admits the peer that presented `token` until the token expires.
+    pub fn redeem(
+        &mut self,
+        token: &InviteToken,
+        presented_by: PeerId,
+        relay_key: &identity::PublicKey,
+    ) -> Result<(), InviteError> {
+        token.verify(relay_key, presented_by)?;
+
+        // A token expiring too far in the future to represent never expires.
+        let expires_at = UNIX_EPOCH.checked_add(Duration::from_secs(token.expires_at));
+        let quota = self.default_quota;
+        let admission = self
+            .admitted
+            .entry(presented_by)
+            .or_insert(Admission { quota, expires_at });
+        // Never shorten an admission, least of all a permanent one from the allowlist.
+        admission.expires_at = match (admission.expires_at, expires_at) {
+            (Some(current), Some(expires_at)) => Some(current.max(expires_at)),
+            _ => None,
+        };
+
+        Ok(())
+    }
+
+    fn quota(&mut self, peer: &PeerId) -> Option<Quota> {
+        let admission = self.admitted.get(peer)?;
+        if admission
+            .expires_at
+            .is_some_and(|expires_at| expires_at < SystemTime::now())
+        {
+            self.admitted.remove(peer);
+            return None;
+        }
+
+        Some(admission.quota)
+    }
+
+    fn usage(&mut self, peer: PeerId, now: Instant) -> &mut Usage {
+        let usage = self.usage.entry(peer).or_insert(Usage {
+            window_start: now,
+            circuits: 0,
+            bytes: 0,
+            first_reservation: None,
+        });
+        if now.duration_since(usage.window_start) >= QUOTA_WINDOW {
+            usage.window_start = now;
+            usage.circuits = 0;
+            usage.bytes = 0;
+        }
+
+        usage
+    }
+
+    fn try_reserve(&mut self, peer: PeerId, now: Instant) -> bool {
+        let Some(quota) = self.quota(&peer) else {
+            tracing::info!(%peer, "Denying reservation of peer that is not admitted");
+            return false;
+        };
+
+        let usage = self.usage(peer, now);
+        let first_reservation = *usage.first_reservation.get_or_insert(now);
+        match quota.reservation_lifetime {
+            Some(lifetime) if now.duration_since(first_reservation) > lifetime => {
+                tracing::info!(%peer, "Denying reservation, reservation lifetime exhausted");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn try_circuit(&mut self, peer: PeerId, now: Instant) -> bool {
+        let Some(quota) = self.quota(&peer) else {
+            tracing::info!(%peer, "Denying circuit of peer that is not admitted");
+            return false;
+        };
+
+        let circuit_bytes = self.circuit_bytes;
+        let usage = self.usage(peer, now);
+        if usage.circuits >= quota.circuits_per_hour {
+            tracing::info!(%peer, "Denying circuit, circuit quota exhausted");
+            return false;
+        }
+        if usage.bytes.saturating_add(circuit_bytes) > quota.bytes_per_hour {
+            tracing::info!(%peer, "Denying circuit, bandwidth quota exhausted");
+            return false;
+        }
+        usage.circuits += 1;
+        usage.bytes += circuit_bytes;
+
+        true
+    }
+
+    /// Limiter for [`relay::Config::reservation_rate_limiters`] that only lets admitted peers
+    /// within their quota reserve.
+    pub fn reservation_limiter(this: Arc<Mutex<Self>>) -> Box<dyn relay::RateLimiter> {
+        Box::new(move |peer, _: &Multiaddr, now| {
+            this.lock().expect("not poisoned").try_reserve(peer, now)
+        })
+    }
+
+    /// Limiter for [`relay::Config::circuit_src_rate_limiters`] that only lets admitted peers
+    /// within their quota open circuits.
+    pub fn circuit_limiter(this: Arc<Mutex<Self>>) -> Box<dyn relay::RateLimiter> {
+        Box::new(move |peer, _: &Multiaddr, now| {
+            this.lock().expect("not poisoned").try_circuit(peer, now)
+        })
+    }
+}
+
+/// Grants one peer access to a relay until it expires. Issued and verified with the relay's key.
+///
+/// The text form is `<peer-id>.<expiry as unix seconds>.<hex signature>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InviteToken {
+    pub peer: PeerId,
+    /// Unix timestamp in seconds after which the token is no longer accepted.
+    pub expires_at: u64,
+    signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InviteError {
+    Malformed,
+    WrongPeer,
+    Expired,
+    BadSignature,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::Malformed => write!(f, "malformed invite token"),
+            InviteError::WrongPeer => write!(f, "invite token was issued to another peer"),
+            InviteError::Expired => write!(f, "invite token expired"),
+            InviteError::BadSignature => write!(f, "invite token was not issued by this relay"),
+        }
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+impl InviteToken {
+    /// Issues a token admitting `peer` for `valid_for`, signed with the relay's keypair.
+    ///
+    /// Returns `None` if the token would expire too far in the future to represent.
+    pub fn issue(relay_key: &identity::Keypair, peer: PeerId, valid_for: Duration) -> Option<Self> {
+        let expires_at = SystemTime::now()
+            .checked_add(valid_for)?
+            .duration_since(UNIX_EPOCH)
+            .expect("now to be after 1970")
+            .as_secs();
+        let signature = relay_key
+            .sign(&Self::signed_bytes(&peer, expires_at))
+            .expect("signing with an Ed25519 key to succeed");
+
+        Some(Self {
+            peer,
+            expires_at,
+            signature,
+        })
+    }
+
+    /// Checks that the token was issued by `relay_key` to `presented_by` and did not expire.
+    pub fn verify(
+        &self,
+        relay_key: &identity::PublicKey,
+        presented_by: PeerId,
+    ) -> Result<(), InviteError> {
+        if self.peer != presented_by {
+            return Err(InviteError::WrongPeer);
+        }
+        if !relay_key.verify(
+            &Self::signed_bytes(&self.peer, self.expires_at),
+            &self.signature,
+        ) {
+            return Err(InviteError::BadSignature);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        if self.expires_at < now {
+            return Err(InviteError::Expired);
+        }
+
+        Ok(())
+    }
+
+    fn signed_bytes(peer: &PeerId, expires_at: u64) -> Vec<u8> {
+        let mut bytes = INVITE_PROTOCOL.as_ref().as_bytes().to_vec();
+        bytes.extend_from_slice(&peer.to_bytes());
+        bytes.extend_from_slice(&expires_at.to_be_bytes());
+        bytes
+    }
+}
+
+impl fmt::Display for InviteToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.peer,
+            self.expires_at,
+            hex::encode(&self.signature)
+        )
+    }
+}
+
+impl FromStr for InviteToken {
+    type Err = InviteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split('.');
+        let (Some(peer), Some(expires_at), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(InviteError::Malformed);
+        };
+
+        Ok(Self {
+            peer: peer.parse().map_err(|_| InviteError::Malformed)?,
+            expires_at: expires_at.parse().map_err(|_| InviteError::Malformed)?,
+            signature: hex::decode(signature).map_err(|_| InviteError::Malformed)?,
+        })
+    }
+}
+
+/// Answer of the relay to a redeemed invite token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InviteResponse {
+    Accepted,
+    Rejected(String),
+}
+
+// === Invite Protocol Definition ===
+
+#[derive(Clone, Default)]
+pub struct InviteCodec();
+
+#[async_trait]
+impl request_response::Codec for InviteCodec {
+    type Protocol = StreamProtocol;
+    type Request = InviteToken;
+    type Response = InviteResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = String::new();
+        io.take(MAX_INVITE_SIZE).read_to_string(&mut buf).await?;
+        buf.parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut buf = String::new();
+        io.take(MAX_INVITE_SIZE).read_to_string(&mut buf).await?;
+        match buf.split_once(' ') {
+            _ if buf == "OK" => Ok(InviteResponse::Accepted),
+            Some(("REJECTED", reason)) => Ok(InviteResponse::Rejected(reason.to_string())),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected invite response",
+            )),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        token: InviteToken,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(token.to_string().as_bytes()).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: InviteResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = match response {
+            InviteResponse::Accepted => "OK".to_string(),
+            InviteResponse::Rejected(reason) => format!("REJECTED {reason}"),
+        };
+        io.write_all(data.as_bytes()).await?;
+        io.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA: Quota = Quota {
+        circuits_per_hour: 2,
+        bytes_per_hour: 3 * 1024,
+        reservation_lifetime: Some(Duration::from_secs(60)),
+    };
+
+    /// A token for `peer` expiring at `expires_at`, which [`InviteToken::issue`] cannot produce
+    /// for timestamps in the past.
+    fn signed(relay_key: &identity::Keypair, peer: PeerId, expires_at: u64) -> InviteToken {
+        InviteToken {
+            peer,
+            expires_at,
+            signature: relay_key
+                .sign(&InviteToken::signed_bytes(&peer, expires_at))
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn token_roundtrip() {
+        let relay_key = identity::Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let token = InviteToken::issue(&relay_key, peer, Duration::from_secs(60)).unwrap();
+
+        let parsed: InviteToken = token.to_string().parse().unwrap();
+        assert_eq!(parsed, token);
+        assert_eq!(parsed.verify(&relay_key.public(), peer), Ok(()));
+    }
+
+    #[test]
+    fn overlong_validity_is_refused() {
+        let relay_key = identity::Keypair::generate_ed25519();
+        let valid_for = Duration::from_secs(u64::MAX);
+        assert!(InviteToken::issue(&relay_key, PeerId::random(), valid_for).is_none());
+    }
+
+    #[test]
+    fn token_for_another_peer_is_rejected() {
+        let relay_key = identity::Keypair::generate_ed25519();
+        let token = InviteToken::issue(&relay_key, PeerId::random(), Duration::from_secs(60));
+        assert_eq!(
+            token.unwrap().verify(&relay_key.public(), PeerId::random()),
+            Err(InviteError::WrongPeer)
+        );
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let relay_key = identity::Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let token = InviteToken::issue(&relay_key, peer, Duration::from_secs(60)).unwrap();
+
+        let mut extended = token.clone();
+        extended.expires_at += 60;
+        assert_eq!(
+            extended.verify(&relay_key.public(), peer),
+            Err(InviteError::BadSignature)
+        );
+
+        let mut forged = token.clone();
+        forged.signature[0] ^= 1;
+        assert_eq!(
+            forged.verify(&relay_key.public(), peer),
+            Err(InviteError::BadSignature)
+        );
+
+        let other_relay = identity::Keypair::generate_ed25519();
+        assert_eq!(
+            token.verify(&other_relay.public(), peer),
+            Err(InviteError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let relay_key = identity::Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let token = signed(&relay_key, peer, 1_000_000_000);
+        assert_eq!(
+            token.verify(&relay_key.public(), peer),
+            Err(InviteError::Expired)
+        );
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let token = signed(&identity::Keypair::generate_ed25519(), PeerId::random(), 0);
+        let text = token.to_string();
+        let (peer, rest) = text.split_once('.').unwrap();
+        for malformed in [
+            String::new(),
+            peer.to_string(),
+            format!("{text}.00"),
+            format!("{peer}.soon.{}", hex::encode(&token.signature)),
+            format!("{peer}.0.not-hex"),
+            format!("not-a-peer.{rest}"),
+        ] {
+            assert_eq!(
+                malformed.parse::<InviteToken>(),
+                Err(InviteError::Malformed),
+                "{malformed}"
+            );
+        }
+    }
+
+    #[test]
+    fn allowlist_lines_override_the_default_quota() {
+        let access = AccessControl::new(QUOTA, 1024);
+        let peer = PeerId::random();
+
+        assert_eq!(
+            access.parse_allowlist_line(&peer.to_string()),
+            Ok((peer, QUOTA))
+        );
+        assert_eq!(
+            access.parse_allowlist_line(&format!("{peer} circuits=10 bytes=4096 lifetime=3600")),
+            Ok((
+                peer,
+                Quota {
+                    circuits_per_hour: 10,
+                    bytes_per_hour: 4096,
+                    reservation_lifetime: Some(Duration::from_secs(3600)),
+                }
+            ))
+        );
+        for invalid in [
+            "not-a-peer".to_string(),
+            format!("{peer} circuits"),
+            format!("{peer} circuits=many"),
+            format!("{peer} hours=1"),
+        ] {
+            assert!(access.parse_allowlist_line(&invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn allowlist_file_skips_comments_and_reports_bad_lines() {
+        let path = std::env::temp_dir().join(format!("hermes-allowlist-{}", PeerId::random()));
+        let (listed, commented) = (PeerId::random(), PeerId::random());
+        fs::write(
+            &path,
+            format!("# peers\n\n{listed} circuits=5 # a friend\n# {commented}\n"),
+        )
+        .unwrap();
+        let mut access = AccessControl::new(QUOTA, 1024);
+        access.load_allowlist(&path).unwrap();
+        assert_eq!(
+            access.quota(&listed).map(|quota| quota.circuits_per_hour),
+            Some(5)
+        );
+        assert_eq!(access.quota(&commented), None);
+
+        fs::write(&path, format!("{listed}\n{listed} circuits=x\n")).unwrap();
+        let error = access.load_allowlist(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains(":2: "), "{error}");
+    }
+
+    #[test]
+    fn only_admitted_peers_may_use_the_relay() {
+        let relay_key = identity::Keypair::generate_ed25519();
+        let mut access = AccessControl::new(QUOTA, 1024);
+        let now = Instant::now();
+        let (stranger, invited, expired) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        assert!(!access.try_reserve(stranger, now));
+        assert!(!access.try_circuit(stranger, now));
+
+        let token = InviteToken::issue(&relay_key, invited, Duration::from_secs(60)).unwrap();
+        access.redeem(&token, invited, &relay_key.public()).unwrap();
+        assert!(access.try_reserve(invited, now));
+        assert!(access.try_circuit(invited, now));
+
+        // Redeemed before it expired, the admission still ends with the token.
+        access.admitted.insert(
+            expired,
+            Admission {
+                quota: QUOTA,
+                expires_at: Some(SystemTime::now() - Duration::from_secs(1)),
+            },
+        );
+        assert!(!access.try_reserve(expired, now));
+        assert!(!access.admitted.contains_key(&expired));
+    }
+
+    #[test]
+    fn circuits_are_limited_per_hour() {
+        let mut access = AccessControl::new(QUOTA, 1024);
+        let peer = PeerId::random();
+        access.allow(peer, None);
+        let now = Instant::now();
+
+        assert!(access.try_circuit(peer, now));
+        assert!(access.try_circuit(peer, now));
+        assert!(!access.try_circuit(peer, now));
+        assert!(access.try_circuit(peer, now + QUOTA_WINDOW));
+    }
+
+    #[test]
+    fn circuits_are_charged_against_the_byte_quota() {
+        let mut access = AccessControl::new(
+            Quota {
+                circuits_per_hour: 10,
+                ..QUOTA
+            },
+            2 * 1024,
+        );
+        let peer = PeerId::random();
+        access.allow(peer, None);
+        let now = Instant::now();
+
+        // A second circuit would exceed the 3 KiB per hour.
+        assert!(access.try_circuit(peer, now));
+        assert!(!access.try_circuit(peer, now));
+        assert!(access.try_circuit(peer, now + QUOTA_WINDOW));
+    }
+
+    #[test]
+    fn reservations_end_with_the_lifetime() {
+        let mut access = AccessControl::new(QUOTA, 1024);
+        let (limited, unlimited) = (PeerId::random(), PeerId::random());
+        access.allow(limited, None);
+        access.allow(
+            unlimited,
+            Some(Quota {
+                reservation_lifetime: None,
+                ..QUOTA
+            }),
+        );
+        let now = Instant::now();
+        let later = now + Duration::from_secs(61);
+
+        assert!(access.try_reserve(limited, now));
+        assert!(access.try_reserve(limited, now + Duration::from_secs(60)));
+        assert!(!access.try_reserve(limited, later));
+        assert!(access.try_reserve(unlimited, now));
+        assert!(access.try_reserve(unlimited, later));
+    }
+}
@@ -0,0 +1,36 @@
+//! Exponential backoff with jitter, so peers that lost the same relay do not all retry at once.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// The delay before the next attempt: the current delay plus up to half of it as jitter.
+    /// Doubles the delay for the attempt after, up to the maximum.
+    pub fn next_delay(&mut self) -> Duration {
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.current / 2);
+        let delay = self.current + jitter;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    /// Starts over with the initial delay, once an attempt succeeded.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
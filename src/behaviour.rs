@@ -0,0 +1,191 @@
+//! The network behaviour shared by every hermes subcommand that joins the chat network.
+
+use std::{error::Error, time::Duration};
+
+use libp2p::{
+    autonat,
+    core::{transport::OptionalTransport, upgrade},
+    dcutr, gossipsub, identify, identity, kad, mdns, noise, ping, quic, relay, rendezvous,
+    request_response,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
+    tcp, upnp, yamux, Swarm, Transport,
+};
+use tokio::io;
+
+use crate::{
+    access::{self, InviteCodec},
+    dht, envelope,
+    lookup::{self, LookupCodec},
+    scoring,
+};
+
+/// Protocol version announced via identify. Peers with a different version are not ours.
+pub const PROTOCOL_VERSION: &str = "/hermes/1.0.0";
+
+// We create a custom network behaviour that combines DCUtR, Relay, Identify, Ping, Gossipsub,
+// Rendezvous, Kademlia, mDNS, AutoNAT and UPnP.
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    pub relay_client: relay::client::Behaviour,
+    pub ping: ping::Behaviour,
+    pub identify: identify::Behaviour,
+    pub dcutr: dcutr::Behaviour,
+    pub gossipsub: gossipsub::Behaviour,
+    /// Redeems invite tokens with relays that restrict access.
+    pub invite: request_response::Behaviour<InviteCodec>,
+    /// Registers with and discovers room members through a rendezvous server.
+    pub rendezvous: rendezvous::client::Behaviour,
+    /// Resolves usernames by asking connected peers.
+    pub lookup: request_response::Behaviour<LookupCodec>,
+    /// Holds username and peer address records when no directory node knows them.
+    pub kad: kad::Behaviour<kad::store::MemoryStore>,
+    /// Finds peers on the local network. Only enabled in LAN mode.
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    /// Asks connected peers to dial us back, to learn whether we are reachable from outside.
+    pub autonat: autonat::Behaviour,
+    /// Maps our listen ports on the gateway and confirms the mapped addresses as external.
+    /// Only enabled on request.
+    pub upnp: Toggle<upnp::tokio::Behaviour>,
+}
+
+/// Optional behaviours and transports of [`build_swarm`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SwarmOptions {
+    /// Discover peers on the local network via mDNS.
+    pub lan: bool,
+    /// Map listen ports on the gateway via UPnP.
+    pub upnp: bool,
+    pub transports: Transports,
+    /// How hard gossipsub pushes back on misbehaving peers.
+    pub scoring: scoring::Profile,
+}
+
+/// The transports a swarm dials and listens with, besides DNS and relay circuits.
+#[derive(Debug, Clone, Copy)]
+pub struct Transports {
+    pub tcp: bool,
+    pub quic: bool,
+    /// Dial TCP connections from the listen port, so a NAT maps both to the same public port,
+    /// which hole punching relies on.
+    pub tcp_port_reuse: bool,
+}
+
+impl Default for Transports {
+    fn default() -> Self {
+        Self {
+            tcp: true,
+            quic: true,
+            tcp_port_reuse: true,
+        }
+    }
+}
+
+/// Builds a swarm for `keypair` with the selected TCP and QUIC transports, DNS and the relay
+/// client transport.
+pub fn build_swarm(
+    keypair: identity::Keypair,
+    options: SwarmOptions,
+) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
+    let transports = options.transports;
+    if !transports.tcp && !transports.quic {
+        return Err("At least one of TCP and QUIC has to be enabled.".into());
+    }
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_other_transport(|key| -> Result<_, Box<dyn Error + Send + Sync>> {
+            if !transports.tcp {
+                return Ok(OptionalTransport::none());
+            }
+            let config = tcp::Config::default()
+                .nodelay(true)
+                .port_reuse(transports.tcp_port_reuse);
+            Ok(OptionalTransport::some(
+                tcp::tokio::Transport::new(config)
+                    .upgrade(upgrade::Version::V1Lazy)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()),
+            ))
+        })?
+        .with_other_transport(|key| {
+            if transports.quic {
+                OptionalTransport::some(quic::tokio::Transport::new(quic::Config::new(key)))
+            } else {
+                OptionalTransport::none()
+            }
+        })?
+        .with_dns()?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|key, relay_behaviour| {
+            let gossipsub = new_gossipsub(key, options.scoring)?;
+            let mdns = options
+                .lan
+                .then(|| {
+                    mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())
+                })
+                .transpose()?;
+
+            Ok(Behaviour {
+                relay_client: relay_behaviour,
+                ping: ping::Behaviour::new(ping::Config::new()),
+                identify: identify::Behaviour::new(identify::Config::new(
+                    PROTOCOL_VERSION.to_string(),
+                    key.public(),
+                )),
+                dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
+                gossipsub,
+                invite: request_response::Behaviour::new(
+                    [(access::INVITE_PROTOCOL, request_response::ProtocolSupport::Outbound)],
+                    request_response::Config::default(),
+                ),
+                rendezvous: rendezvous::client::Behaviour::new(key.clone()),
+                lookup: request_response::Behaviour::new(
+                    [(lookup::PROTOCOL, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                ),
+                kad: dht::new_behaviour(key.public().to_peer_id()),
+                mdns: Toggle::from(mdns),
+                autonat: autonat::Behaviour::new(
+                    key.public().to_peer_id(),
+                    autonat::Config {
+                        // Probe soon after connecting to the relay instead of after 15 seconds.
+                        boot_delay: Duration::from_secs(5),
+                        ..Default::default()
+                    },
+                ),
+                upnp: Toggle::from(options.upnp.then(upnp::tokio::Behaviour::default)),
+            })
+        })?
+        .build();
+
+    Ok(swarm)
+}
+
+/// Builds the gossipsub behaviour every node on the chat network uses, scoring peers by
+/// `profile`.
+pub fn new_gossipsub(
+    key: &identity::Keypair,
+    profile: scoring::Profile,
+) -> Result<gossipsub::Behaviour, Box<dyn Error + Send + Sync>> {
+    // Set a custom gossipsub configuration.
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+        .heartbeat_interval(Duration::from_secs(10))
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        // Messages are only forwarded once the node accepted them.
+        .validate_messages()
+        // Identify messages by sender and sequence number, never by content alone.
+        .message_id_fn(envelope::gossip_message_id)
+        .build()
+        .map_err(io::Error::other)?;
+
+    // Build a gossipsub network behaviour.
+    let mut gossipsub = gossipsub::Behaviour::new(
+        gossipsub::MessageAuthenticity::Signed(key.clone()),
+        gossipsub_config,
+    )?;
+    // Scoring also lets the application lower the score of peers that forward invalid messages.
+    gossipsub
+        .with_peer_score(profile.peer_score_params(), profile.thresholds())
+        .map_err(io::Error::other)?;
+
+    Ok(gossipsub)
+}
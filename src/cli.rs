@@ -0,0 +1,355 @@
+//! Command line options shared by several subcommands.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::{Args, ValueEnum};
+use libp2p::{core::multiaddr::Protocol, identity, Multiaddr, PeerId};
+
+use crate::{
+    access::InviteToken,
+    behaviour::Transports,
+    keystore,
+    node::HolePunchRetry,
+    portmap,
+    scoring::{Profile, SpamProtection},
+    startup::Timeouts,
+    validation::RateLimit,
+};
+
+/// Selects the identity keypair of the local node.
+#[derive(Debug, Args)]
+pub struct IdentityOpts {
+    /// File holding this node's identity keypair. Generated on first run.
+    #[arg(long, default_value_os_t = keystore::default_identity_path())]
+    pub identity_file: PathBuf,
+
+    /// Use a deterministic identity derived from `--secret-key-seed` instead of the identity file.
+    /// Anyone can recreate such keys, so only use this for local tests.
+    #[arg(long, requires = "secret_key_seed")]
+    pub insecure_test_identity: bool,
+
+    /// Fixed value to generate deterministic peer id. Requires `--insecure-test-identity`.
+    #[arg(long, requires = "insecure_test_identity")]
+    pub secret_key_seed: Option<u8>,
+}
+
+impl IdentityOpts {
+    /// Loads or generates the keypair selected by these options.
+    pub fn keypair(&self) -> io::Result<identity::Keypair> {
+        keystore::resolve_identity(&self.identity_file, self.secret_key_seed)
+    }
+}
+
+/// How a client reaches its remote peer through the relay.
+#[derive(Debug, Args)]
+pub struct RelayClientOpts {
+    /// Whether to listen for the remote peer through the relay or dial it (listen, dial).
+    #[arg(long, value_enum, default_value_t = Mode::Listen)]
+    pub mode: Mode,
+
+    /// The listening address of a relay server. May be given multiple times, to listen through
+    /// several relays at once and fail over between them. Required unless chatting in LAN mode.
+    #[arg(long = "relay-address")]
+    pub relay_addresses: Vec<Multiaddr>,
+
+    /// Peer ID of the remote peer to hole punch to. Required in dial mode, unless the peers are
+    /// discovered through a rendezvous server.
+    #[arg(long)]
+    pub remote_peer_id: Option<PeerId>,
+
+    /// Invite token issued by the relay operator, redeemed before reserving or dialing.
+    /// Not needed on open relays or if this peer is on the relay's allowlist.
+    #[arg(long)]
+    pub invite_token: Option<InviteToken>,
+
+    /// Ask the router to forward our listen ports via UPnP, so peers behind other NATs can dial
+    /// us directly. The router has to support UPnP IGD and have a public address.
+    #[arg(long)]
+    pub upnp: bool,
+
+    /// Map our listen ports on the UPnP gateway answering SSDP searches at this address,
+    /// instead of searching the network for one. Also accepts gateways without a public
+    /// address, such as a local IGD stand-in for testing.
+    #[arg(long, conflicts_with = "nat_pmp_gateway")]
+    pub upnp_gateway: Option<SocketAddr>,
+
+    /// Map our listen ports via NAT-PMP on the router at this address.
+    #[arg(long)]
+    pub nat_pmp_gateway: Option<Ipv4Addr>,
+
+    /// How long a single attempt to connect to the relay may take, and how long the relay may
+    /// take to answer once connected.
+    #[arg(long, default_value_t = 10)]
+    pub relay_timeout_secs: u64,
+
+    /// How often to try connecting to the relay before giving up.
+    #[arg(long, default_value_t = 3)]
+    pub relay_connect_attempts: u32,
+
+    /// How often to retry a failed hole punch, through a fresh relayed connection.
+    #[arg(long, default_value_t = 2)]
+    pub hole_punch_retries: u32,
+
+    /// Delay before the first hole punch retry, doubled for every further one.
+    #[arg(long, default_value_t = 10)]
+    pub hole_punch_retry_secs: u64,
+}
+
+impl RelayClientOpts {
+    /// The startup timeouts selected by these options.
+    pub fn timeouts(&self) -> Timeouts {
+        let timeout = Duration::from_secs(self.relay_timeout_secs);
+        Timeouts {
+            connect: timeout,
+            connect_attempts: self.relay_connect_attempts,
+            identify: timeout,
+            invite: timeout,
+            ..Default::default()
+        }
+    }
+
+    /// The gateway to map ports on ourselves, if one was given.
+    pub fn port_mapping(&self) -> Option<portmap::Gateway> {
+        match (self.upnp_gateway, self.nat_pmp_gateway) {
+            (Some(address), _) => Some(portmap::Gateway::Upnp(address)),
+            (None, Some(ip)) => Some(portmap::Gateway::NatPmp(SocketAddr::from((
+                ip,
+                portmap::NAT_PMP_PORT,
+            )))),
+            (None, None) => None,
+        }
+    }
+
+    /// The hole punch retry policy selected by these options.
+    pub fn hole_punch_retry(&self) -> HolePunchRetry {
+        HolePunchRetry {
+            attempts: self.hole_punch_retries,
+            backoff: Duration::from_secs(self.hole_punch_retry_secs),
+        }
+    }
+}
+
+/// How a chat client finds the other members of its rooms.
+#[derive(Debug, Args)]
+pub struct DiscoveryOpts {
+    /// Address of a rendezvous server, ending in `/p2p/<peer-id>`. Members of a room register
+    /// there and are dialed automatically.
+    #[arg(long)]
+    pub rendezvous_address: Option<Multiaddr>,
+
+    /// Address of a directory node, ending in `/p2p/<peer-id>`. It answers username lookups,
+    /// also for peers that announced themselves before we joined.
+    #[arg(long)]
+    pub directory_address: Option<Multiaddr>,
+
+    /// Find peers on the local network via mDNS and connect to them directly. Without
+    /// `--relay-address`, no relay is used at all.
+    #[arg(long)]
+    pub lan: bool,
+
+    /// Directory to remember known peers and their addresses in, so they are redialed on the
+    /// next start. Defaults to one per identity under `~/.hermes/peers`.
+    #[arg(long)]
+    pub peerstore: Option<PathBuf>,
+
+    /// Do not remember peers across restarts.
+    #[arg(long, conflicts_with = "peerstore")]
+    pub no_peerstore: bool,
+}
+
+/// Which transports a client uses and where it listens.
+#[derive(Debug, Args)]
+pub struct TransportOpts {
+    /// Address to listen on, instead of the wildcard addresses selected by the options below.
+    /// May be given multiple times.
+    #[arg(long = "listen-address")]
+    pub listen_addresses: Vec<Multiaddr>,
+
+    /// IP versions to listen on. `dual` listens on IPv4 and IPv6.
+    #[arg(long, value_enum, default_value_t = IpStack::V4)]
+    pub ip: IpStack,
+
+    /// TCP port to listen on. 0 picks a free one.
+    #[arg(long, default_value_t = 0)]
+    pub tcp_port: u16,
+
+    /// UDP port to listen on for QUIC. 0 picks a free one. Set it if the firewall only lets
+    /// specific UDP ports through.
+    #[arg(long, default_value_t = 0)]
+    pub quic_port: u16,
+
+    /// Do not use TCP.
+    #[arg(long, conflicts_with = "no_quic")]
+    pub no_tcp: bool,
+
+    /// Do not use QUIC, for networks that block UDP.
+    #[arg(long)]
+    pub no_quic: bool,
+
+    /// Dial TCP connections from a fresh port instead of the listen port. Reusing the listen
+    /// port makes hole punching more likely to succeed.
+    #[arg(long)]
+    pub no_tcp_port_reuse: bool,
+}
+
+impl TransportOpts {
+    /// The transports selected by these options.
+    pub fn transports(&self) -> Transports {
+        Transports {
+            tcp: !self.no_tcp,
+            quic: !self.no_quic,
+            tcp_port_reuse: !self.no_tcp_port_reuse,
+        }
+    }
+
+    /// The addresses to listen on: the explicit ones, or the wildcard address of every selected
+    /// IP version and transport.
+    pub fn listen_addresses(&self) -> Vec<Multiaddr> {
+        if !self.listen_addresses.is_empty() {
+            return self.listen_addresses.clone();
+        }
+        let ips: &[IpAddr] = match self.ip {
+            IpStack::V4 => &[IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            IpStack::V6 => &[IpAddr::V6(Ipv6Addr::UNSPECIFIED)],
+            IpStack::Dual => &[
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ],
+        };
+        let mut addresses = Vec::new();
+        for ip in ips {
+            let ip = Multiaddr::from(*ip);
+            if !self.no_quic {
+                addresses.push(
+                    ip.clone()
+                        .with(Protocol::Udp(self.quic_port))
+                        .with(Protocol::QuicV1),
+                );
+            }
+            if !self.no_tcp {
+                addresses.push(ip.with(Protocol::Tcp(self.tcp_port)));
+            }
+        }
+        addresses
+    }
+}
+
+/// How a chat client protects itself and its rooms against spam.
+#[derive(Debug, Args)]
+pub struct SpamOpts {
+    /// Peer scoring and rate limits to apply. `strict` cuts off misbehaving peers sooner.
+    #[arg(long, value_enum, default_value_t = Profile::Standard)]
+    pub spam_profile: Profile,
+
+    /// Messages a peer may send per `--rate-limit-secs` before further ones are dropped.
+    /// Defaults to the limit of the profile.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+
+    /// Period in seconds that `--rate-limit` counts messages over. Requires `--rate-limit`;
+    /// without it the limit and period of the profile apply.
+    #[arg(
+        long,
+        default_value_t = 10,
+        requires = "rate_limit",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub rate_limit_secs: u64,
+
+    /// Messages over the rate limit after which their author is blacklisted. Defaults to the
+    /// limit of the profile.
+    #[arg(long)]
+    pub flood_limit: Option<u32>,
+}
+
+impl SpamOpts {
+    /// The spam protection selected by these options.
+    pub fn spam_protection(&self) -> SpamProtection {
+        let mut spam = SpamProtection::from(self.spam_profile);
+        if let Some(messages) = self.rate_limit {
+            spam.rate_limit = RateLimit {
+                messages,
+                per: Duration::from_secs(self.rate_limit_secs),
+            };
+        }
+        if let Some(flood_limit) = self.flood_limit {
+            spam.flood_limit = flood_limit;
+        }
+        spam
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IpStack {
+    V4,
+    V6,
+    Dual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    Dial,
+    Listen,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        spam: SpamOpts,
+    }
+
+    fn spam_opts(args: &[&str]) -> Result<SpamOpts, clap::Error> {
+        Cli::try_parse_from(std::iter::once("hermes").chain(args.iter().copied()))
+            .map(|cli| cli.spam)
+    }
+
+    #[test]
+    fn spam_protection_defaults_to_the_profile() {
+        let spam = spam_opts(&["--spam-profile", "relaxed"])
+            .unwrap()
+            .spam_protection();
+        assert_eq!(spam.profile, Profile::Relaxed);
+        assert_eq!(
+            spam.rate_limit.messages,
+            Profile::Relaxed.rate_limit().messages
+        );
+        assert_eq!(spam.rate_limit.per, Profile::Relaxed.rate_limit().per);
+        assert_eq!(spam.flood_limit, Profile::Relaxed.flood_limit());
+    }
+
+    #[test]
+    fn limits_override_the_profile() {
+        let spam = spam_opts(&[
+            "--rate-limit",
+            "3",
+            "--rate-limit-secs",
+            "60",
+            "--flood-limit",
+            "7",
+        ])
+        .unwrap()
+        .spam_protection();
+        assert_eq!(spam.profile, Profile::Standard);
+        assert_eq!(spam.rate_limit.messages, 3);
+        assert_eq!(spam.rate_limit.per, Duration::from_secs(60));
+        assert_eq!(spam.flood_limit, 7);
+    }
+
+    #[test]
+    fn rate_limits_must_be_positive() {
+        assert!(spam_opts(&["--rate-limit", "0"]).is_err());
+        assert!(spam_opts(&["--rate-limit", "1", "--rate-limit-secs", "0"]).is_err());
+        assert!(spam_opts(&["--rate-limit-secs", "5"]).is_err());
+    }
+}
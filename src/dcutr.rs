@@ -18,214 +18,163 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-// #![doc = include_str!("../README.md")]
+//! Establishes a direct connection to a remote peer by hole punching through a relay.
 
-use std::{error::Error, str::FromStr};
+use std::error::Error;
 
-use clap::Parser;
-use futures::{executor::block_on, future::FutureExt, stream::StreamExt};
-use libp2p::{
-    core::multiaddr::{Multiaddr, Protocol},
-    dcutr, identify, identity, noise, ping, relay,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, PeerId,
-};
-use tracing_subscriber::EnvFilter;
-
-#[derive(Debug, Parser)]
-#[command(name = "libp2p DCUtR client")]
-struct Opts {
-    /// The mode (client-listen, client-dial).
-    #[arg(long)]
-    mode: Mode,
+use clap::Args;
+use futures::stream::StreamExt;
+use libp2p::{autonat, core::multiaddr::Protocol, dcutr, relay, swarm::SwarmEvent};
 
-    /// Fixed value to generate deterministic peer id.
-    #[arg(long)]
-    secret_key_seed: u8,
+use crate::{
+    behaviour::{self, BehaviourEvent, SwarmOptions},
+    cli::{IdentityOpts, Mode, RelayClientOpts, TransportOpts},
+    startup::Startup,
+};
 
-    /// The listening address
-    #[arg(long)]
-    relay_address: Multiaddr,
+#[derive(Debug, Args)]
+pub struct Opts {
+    #[command(flatten)]
+    pub identity: IdentityOpts,
 
-    /// Peer ID of the remote peer to hole punch to.
-    #[arg(long)]
-    remote_peer_id: Option<PeerId>,
-}
+    #[command(flatten)]
+    pub relay: RelayClientOpts,
 
-#[derive(Clone, Debug, PartialEq, Parser)]
-enum Mode {
-    Dial,
-    Listen,
+    #[command(flatten)]
+    pub transport: TransportOpts,
 }
 
-impl FromStr for Mode {
-    type Err = String;
-    fn from_str(mode: &str) -> Result<Self, Self::Err> {
-        match mode {
-            "dial" => Ok(Mode::Dial),
-            "listen" => Ok(Mode::Listen),
-            _ => Err("Expected either 'dial' or 'listen'".to_string()),
-        }
+pub async fn run(opts: Opts) -> Result<(), Box<dyn Error>> {
+    let Opts {
+        identity,
+        relay: opts,
+        transport,
+    } = opts;
+    if opts.port_mapping().is_some() {
+        return Err("A given port mapping gateway is only supported by the chat.".into());
     }
-}
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .try_init();
-
-    let opts = Opts::parse();
-
-    #[derive(NetworkBehaviour)]
-    struct Behaviour {
-        relay_client: relay::client::Behaviour,
-        ping: ping::Behaviour,
-        identify: identify::Behaviour,
-        dcutr: dcutr::Behaviour,
+    // Hole punching needs a single relay, later ones are ignored.
+    let relay_address = opts
+        .relay_addresses
+        .first()
+        .cloned()
+        .ok_or("Hole punching requires --relay-address.")?;
+    let mut swarm = behaviour::build_swarm(
+        identity.keypair()?,
+        SwarmOptions {
+            upnp: opts.upnp,
+            transports: transport.transports(),
+            ..Default::default()
+        },
+    )?;
+
+    for address in transport.listen_addresses() {
+        swarm.listen_on(address)?;
     }
 
-    let mut swarm =
-        libp2p::SwarmBuilder::with_existing_identity(generate_ed25519(opts.secret_key_seed))
-            .with_tokio()
-            .with_tcp(
-                tcp::Config::default().nodelay(true),
-                noise::Config::new,
-                yamux::Config::default,
-            )?
-            .with_quic()
-            .with_dns()?
-            .with_relay_client(noise::Config::new, yamux::Config::default)?
-            .with_behaviour(|keypair, relay_behaviour| Behaviour {
-                relay_client: relay_behaviour,
-                ping: ping::Behaviour::new(ping::Config::new()),
-                identify: identify::Behaviour::new(identify::Config::new(
-                    "/TODO/0.0.1".to_string(),
-                    keypair.public(),
-                )),
-                dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
-            })?
-            .build();
-
-    swarm
-        .listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap())
-        .unwrap();
-    swarm
-        .listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())
-        .unwrap();
-
-    // Wait to listen on all interfaces.
-    block_on(async {
-        let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
-        loop {
-            futures::select! {
-                event = swarm.next() => {
-                    match event.unwrap() {
-                        SwarmEvent::NewListenAddr { address, .. } => {
-                            tracing::info!(%address, "Listening on address");
-                        }
-                        event => panic!("{event:?}"),
-                    }
-                }
-                _ = delay => {
-                    // Likely listening on all interfaces now, thus continuing by breaking the loop.
-                    break;
-                }
-            }
-        }
-    });
-
     // Connect to the relay server. Not for the reservation or relayed connection, but to (a) learn
     // our local public address and (b) enable a freshly started relay to learn its public address.
-    swarm.dial(opts.relay_address.clone()).unwrap();
-    block_on(async {
-        let mut learned_observed_addr = false;
-        let mut told_relay_observed_addr = false;
-
-        loop {
-            match swarm.next().await.unwrap() {
-                SwarmEvent::NewListenAddr { .. } => {}
-                SwarmEvent::Dialing { .. } => {}
-                SwarmEvent::ConnectionEstablished { .. } => {}
-                SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
-                SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Sent {
-                    ..
-                })) => {
-                    tracing::info!("Told relay its public address");
-                    told_relay_observed_addr = true;
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
-                    info: identify::Info { observed_addr, .. },
-                    ..
-                })) => {
-                    tracing::info!(address=%observed_addr, "Relay told us our observed address");
-                    learned_observed_addr = true;
-                }
-                event => panic!("{event:?}"),
-            }
-
-            if learned_observed_addr && told_relay_observed_addr {
-                break;
-            }
+    // Also redeems the invite token before the relay would refuse our reservation or circuit.
+    let timeouts = opts.timeouts();
+    let mut startup = Startup::new(&mut swarm, timeouts, |event| match event {
+        SwarmEvent::NewListenAddr { address, .. } => {
+            tracing::info!(%address, "Listening on address");
         }
+        event => tracing::debug!(?event, "Startup event"),
     });
+    startup.wait_for_listeners().await;
+    let relay = startup
+        .connect_relay(&relay_address, opts.invite_token)
+        .await?;
+
+    // Let the relay tell us whether we are reachable, for the logs of a failed hole punch.
+    swarm
+        .behaviour_mut()
+        .autonat
+        .add_server(relay.peer_id, Some(relay_address.clone()));
 
     match opts.mode {
         Mode::Dial => {
-            swarm
-                .dial(
-                    opts.relay_address
-                        .with(Protocol::P2pCircuit)
-                        .with(Protocol::P2p(opts.remote_peer_id.unwrap())),
-                )
-                .unwrap();
+            let remote_peer_id = opts
+                .remote_peer_id
+                .ok_or("Dial mode requires --remote-peer-id.")?;
+            swarm.dial(
+                relay_address
+                    .with(Protocol::P2pCircuit)
+                    .with(Protocol::P2p(remote_peer_id)),
+            )?;
         }
         Mode::Listen => {
-            swarm
-                .listen_on(opts.relay_address.with(Protocol::P2pCircuit))
-                .unwrap();
+            swarm.listen_on(relay_address.with(Protocol::P2pCircuit))?;
         }
     }
 
-    block_on(async {
-        loop {
-            match swarm.next().await.unwrap() {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    tracing::info!(%address, "Listening on address");
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
-                    relay::client::Event::ReservationReqAccepted { .. },
-                )) => {
-                    assert!(opts.mode == Mode::Listen);
-                    tracing::info!("Relay accepted our reservation request");
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => {
-                    tracing::info!(?event)
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => {
-                    tracing::info!(?event)
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => {
-                    tracing::info!(?event)
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
-                SwarmEvent::ConnectionEstablished {
-                    peer_id, endpoint, ..
-                } => {
-                    tracing::info!(peer=%peer_id, ?endpoint, "Established new connection");
-                }
-                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                    tracing::info!(peer=?peer_id, "Outgoing connection failed: {error}");
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                tracing::info!(%address, "Listening on address");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted { .. },
+            )) => {
+                if opts.mode != Mode::Listen {
+                    tracing::warn!("Relay accepted a reservation we did not ask for");
                 }
-                _ => {}
+                tracing::info!("Relay accepted our reservation request");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqFailed { error, .. },
+            )) => {
+                tracing::warn!("Relay denied our reservation request: {error}");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::OutboundCircuitReqFailed { error, .. },
+            )) => {
+                tracing::warn!("Relay refused to open a circuit: {error}");
             }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(event)) => {
+                tracing::info!(?event)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
+                dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id },
+            )) => {
+                tracing::info!(peer=%remote_peer_id, "Hole punch succeeded");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
+                dcutr::Event::DirectConnectionUpgradeFailed {
+                    remote_peer_id,
+                    error,
+                },
+            )) => {
+                tracing::warn!(
+                    peer=%remote_peer_id,
+                    reachability=?swarm.behaviour().autonat.nat_status(),
+                    "Hole punch failed: {error}. Run `hermes doctor` to find out why."
+                );
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => {
+                tracing::info!(?event)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged {
+                new,
+                ..
+            })) => {
+                tracing::info!(reachability=?new, "AutoNAT updated our reachability");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => {
+                tracing::info!(?event)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(_)) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(_)) => {}
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                tracing::info!(peer=%peer_id, ?endpoint, "Established new connection");
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                tracing::info!(peer=?peer_id, "Outgoing connection failed: {error}");
+            }
+            _ => {}
         }
-    })
-}
-
-fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
-
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+    }
 }
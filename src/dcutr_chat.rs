@@ -1,307 +1,333 @@
-use clap::Parser;
-use futures::{executor::block_on, future::FutureExt, stream::StreamExt};
-use libp2p::{
-    core::multiaddr::{Multiaddr, Protocol},
-    dcutr, gossipsub, identify, identity, noise, ping, relay,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, PeerId,
-};
-use std::{
-    collections::hash_map::DefaultHasher,
-    error::Error,
-    hash::{Hash, Hasher},
-    str::FromStr,
-    time::Duration,
-};
+//! Gossipsub chat between peers that find each other through a relay and upgrade to direct
+//! connections via DCUtR, or that find each other on the local network via mDNS.
+//!
+//! This is a line based terminal frontend for [`ChatNode`].
+
+use clap::Args;
+use futures::stream::StreamExt;
+use std::error::Error;
 use tokio::{io, io::AsyncBufReadExt, select};
-use tracing_subscriber::EnvFilter;
 
-#[derive(Debug, Parser)]
-#[command(name = "libp2p DCUtR client with chat")]
-struct Opts {
-    /// The mode (client-listen, client-dial).
-    #[arg(long)]
-    mode: Mode,
+use crate::{
+    cli::{DiscoveryOpts, IdentityOpts, RelayClientOpts, SpamOpts, TransportOpts},
+    envelope::Kind,
+    lookup::LookupResult,
+    node::{self, ChatEvent, ChatNode, ConnectionKind, NodeStopped},
+    peerstore,
+    room::DEFAULT_ROOM,
+};
 
-    /// Fixed value to generate deterministic peer id.
-    #[arg(long)]
-    secret_key_seed: u8,
+#[derive(Debug, Args)]
+pub struct Opts {
+    #[command(flatten)]
+    pub identity: IdentityOpts,
 
-    /// The listening address of the relay server.
-    #[arg(long)]
-    relay_address: Multiaddr,
+    #[command(flatten)]
+    pub relay: RelayClientOpts,
 
-    /// Peer ID of the remote peer to hole punch to.
-    #[arg(long)]
-    remote_peer_id: Option<PeerId>,
+    #[command(flatten)]
+    pub discovery: DiscoveryOpts,
 
-    /// The username of the local peer.
-    #[arg(long)]
-    username: String,
-}
+    #[command(flatten)]
+    pub transport: TransportOpts,
 
-#[derive(Clone, Debug, PartialEq, Parser)]
-enum Mode {
-    Dial,
-    Listen,
-}
+    #[command(flatten)]
+    pub spam: SpamOpts,
 
-impl FromStr for Mode {
-    type Err = String;
-    fn from_str(mode: &str) -> Result<Self, Self::Err> {
-        match mode {
-            "dial" => Ok(Mode::Dial),
-            "listen" => Ok(Mode::Listen),
-            _ => Err("Expected either 'dial' or 'listen'".to_string()),
-        }
-    }
-}
+    /// The username of the local peer.
+    #[arg(long)]
+    pub username: String,
 
-// We create a custom network behaviour that combines DCUtR, Relay, Identify, Ping, and Gossipsub.
-#[derive(NetworkBehaviour)]
-struct Behaviour {
-    relay_client: relay::client::Behaviour,
-    ping: ping::Behaviour,
-    identify: identify::Behaviour,
-    dcutr: dcutr::Behaviour,
-    gossipsub: gossipsub::Behaviour,
+    /// Room to join on start. May be given multiple times; messages go to the first one until
+    /// switching with `/switch <room>`.
+    #[arg(long = "room", default_value = DEFAULT_ROOM)]
+    pub rooms: Vec<String>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .try_init();
-
-    let opts = Opts::parse();
-    let keypair = generate_ed25519(opts.secret_key_seed);
-    let local_peer_id = keypair.public().to_peer_id();
-
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair.clone())
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default().nodelay(true),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_quic()
-        .with_dns()?
-        .with_relay_client(noise::Config::new, yamux::Config::default)?
-        .with_behaviour(|key, relay_behaviour| {
-            // Content-address messages by hashing them.
-            let message_id_fn = |message: &gossipsub::Message| {
-                let mut s = DefaultHasher::new();
-                message.data.hash(&mut s);
-                gossipsub::MessageId::from(s.finish().to_string())
-            };
-
-            // Set a custom gossipsub configuration.
-            let gossipsub_config = gossipsub::ConfigBuilder::default()
-                .heartbeat_interval(Duration::from_secs(10))
-                .validation_mode(gossipsub::ValidationMode::Strict)
-                .message_id_fn(message_id_fn)
-                .build()
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-            // Build a gossipsub network behaviour.
-            let gossipsub = gossipsub::Behaviour::new(
-                gossipsub::MessageAuthenticity::Signed(key.clone()),
-                gossipsub_config,
-            )?;
-
-            Ok(Behaviour {
-                relay_client: relay_behaviour,
-                ping: ping::Behaviour::new(ping::Config::new()),
-                identify: identify::Behaviour::new(identify::Config::new(
-                    "/chat/1.0.0".to_string(),
-                    key.public(),
-                )),
-                dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
-                gossipsub,
-            })
-        })?
-        .build();
-
-    // Create a Gossipsub topic and subscribe to it.
-    let topic = gossipsub::IdentTopic::new("dcutr-chat-example");
-    swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
-
-    // Listen on all interfaces.
-    swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-
-    // Wait to listen on all interfaces and print addresses.
-    block_on(async {
-        let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
-        loop {
-            futures::select! {
-                event = swarm.next() => {
-                    if let Some(SwarmEvent::NewListenAddr { address, .. }) = event {
-                        println!("Local node is listening on {address}");
-                    } else {
-                        break;
-                    }
-                }
-                _ = delay => {
-                    break;
-                }
-            }
-        }
-    });
-
-    // Connect to the relay server to learn our external address and enable hole-punching.
-    swarm.dial(opts.relay_address.clone())?;
-    println!("Connecting to relay server at {}", opts.relay_address);
-
-    block_on(async {
-        let mut learned_observed_addr = false;
-        let mut told_relay_observed_addr = false;
-
-        loop {
-            match swarm.next().await.unwrap() {
-                SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Sent { .. })) => {
-                    println!("Told relay its public address.");
-                    told_relay_observed_addr = true;
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
-                    info: identify::Info { observed_addr, .. },
-                    ..
-                })) => {
-                    println!("Relay told us our observed address: {observed_addr}");
-                    learned_observed_addr = true;
-                }
-                SwarmEvent::ConnectionEstablished { .. } => {}
-                _ => {}
-            }
-
-            if learned_observed_addr && told_relay_observed_addr {
-                break;
-            }
-        }
-    });
-
-    // Set up DCUtR connection depending on mode.
-    match opts.mode {
-        Mode::Dial => {
-            let remote_peer_id = opts
-                .remote_peer_id
-                .expect("Dial mode requires a remote peer ID.");
-            let relay_addr_with_circuit = opts
-                .relay_address
-                .with(Protocol::P2pCircuit)
-                .with(Protocol::P2p(remote_peer_id));
-            println!(
-                "Dialing remote peer {} via relay circuit: {}",
-                remote_peer_id, relay_addr_with_circuit
-            );
-            swarm.dial(relay_addr_with_circuit)?;
-        }
-        Mode::Listen => {
-            let relay_addr_with_circuit = opts.relay_address.with(Protocol::P2pCircuit);
-            println!(
-                "Listening for connections via relay circuit: {}",
-                relay_addr_with_circuit
-            );
-            swarm.listen_on(relay_addr_with_circuit)?;
-        }
-    }
-
-    // Inform the bootstrap node of our username and PeerId
-    let msg = format!("PUT_USERNAME {} {}", opts.username, local_peer_id);
-    swarm
-        .behaviour_mut()
-        .gossipsub
-        .publish(topic.clone(), msg.as_bytes())?;
+pub async fn run(opts: Opts) -> Result<(), Box<dyn Error>> {
+    let keypair = opts.identity.keypair()?;
+    let peerstore = match opts.discovery.peerstore {
+        _ if opts.discovery.no_peerstore => None,
+        Some(path) => Some(path),
+        None => Some(peerstore::default_path(&keypair.public().to_peer_id())),
+    };
+    let timeouts = opts.relay.timeouts();
+    let hole_punch_retry = opts.relay.hole_punch_retry();
+    let port_mapping = opts.relay.port_mapping();
+    let (mut node, mut events) = ChatNode::start(node::Config {
+        timeouts,
+        keypair,
+        relay_addresses: opts.relay.relay_addresses,
+        mode: opts.relay.mode,
+        remote_peer_id: opts.relay.remote_peer_id,
+        rendezvous_point: opts.discovery.rendezvous_address,
+        directory: opts.discovery.directory_address,
+        lan: opts.discovery.lan,
+        upnp: opts.relay.upnp,
+        port_mapping,
+        transports: opts.transport.transports(),
+        listen_addresses: opts.transport.listen_addresses(),
+        username: opts.username,
+        rooms: opts.rooms.clone(),
+        invite_token: opts.relay.invite_token,
+        peerstore,
+        hole_punch_retry,
+        spam: opts.spam.spam_protection(),
+    })
+    .await?;
+    println!("Local peer id: {}", node.local_peer_id());
 
     // Read lines from stdin to publish as gossipsub messages
     let mut stdin = io::BufReader::new(io::stdin()).lines();
-    println!("\nEnter messages to send to peers, or use 'DIAL <username>'. Press Ctrl+D to exit.");
-
-    // Clone the relay address outside the loop to avoid borrow checker issues inside select!
-    // let relay_address = opts.relay_address.clone();
-    let relay_addr_with_circuit = relay_address;
+    println!(
+        "\nEnter messages to send to peers, or use 'DIAL <username>' or 'PEERS'. Press Ctrl+D to exit."
+    );
+    println!("Manage rooms with '/join <room>', '/leave [room]', '/rooms' and '/switch <room>'.");
+    println!("Describe what you do with '/me <action>'.");
+    let mut current_room = opts.rooms.first().cloned();
 
     // Main event loop
     loop {
         select! {
             // Handle user input from stdin
-            Ok(Some(line)) = stdin.next_line() => {
-                if line.starts_with("DIAL ") {
-                    if let Some(username) = line.split_whitespace().nth(1) {
-                         let msg = format!("GET {}", username);
-                         swarm.behaviour_mut().gossipsub.publish(topic.clone(), msg.as_bytes())?;
-                    } else {
-                        println!("Usage: DIAL <username>");
+            line = stdin.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                if let Some(username) = line.strip_prefix("DIAL ") {
+                    let username = username.trim();
+                    match node.lookup(username).await? {
+                        LookupResult::Found { peer, addresses } => {
+                            println!("{username} is {peer}, dialing.");
+                            if let Err(e) = node.dial(peer, addresses).await {
+                                println!("Dial error: {e}");
+                            }
+                        }
+                        LookupResult::NotFound => println!("Nobody holds the username {username}."),
+                        LookupResult::Ambiguous { peers } => println!(
+                            "Several peers claim {username}, not dialing any of: {peers:?}"
+                        ),
                     }
-                } else {
-                    if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), line.as_bytes()) {
+                } else if line.trim() == "PEERS" {
+                    for (peer, kind) in node.peers().await? {
+                        println!("{peer}: {}", describe(kind));
+                    }
+                } else if let Some(action) = line.strip_prefix("/me ") {
+                    match &current_room {
+                        Some(room) => {
+                            if let Err(e) = node.send_action(room, action.to_string()).await {
+                                println!("Publish error: {e:?}");
+                            }
+                        }
+                        None => println!("Join a room with '/join <room>' first."),
+                    }
+                } else if line.starts_with('/') {
+                    handle_room_command(&mut node, &mut current_room, &line).await?;
+                } else if let Some(room) = &current_room {
+                    if let Err(e) = node.send(room, line).await {
                         println!("Publish error: {e:?}");
                     }
+                } else {
+                    println!("Join a room with '/join <room>' first.");
                 }
             },
-            // Handle swarm events
-            event = swarm.select_next_some() => match event {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                     println!("Listening on address: {address}");
-                }
-                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
-                     println!("Established new connection with {peer_id} at {endpoint:?}");
-                     // Explicitly add the new peer to gossipsub's mesh.
-                     swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                }
-                SwarmEvent::ConnectionClosed { peer_id, .. } => {
-                    println!("Connection with {peer_id} closed.");
-                    // Remove peer from gossipsub mesh.
-                    swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
-                }
-                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                     println!("Outgoing connection failed to {:?}: {error}", peer_id);
-                }
-                SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
-                    relay::client::Event::ReservationReqAccepted { .. },
-                )) => {
-                    assert!(opts.mode == Mode::Listen, "Should only receive reservation acceptance in listen mode.");
-                    println!("Relay accepted our reservation request.");
+            // Handle network events
+            Some(event) = events.next() => print_event(event),
+        }
+    }
+}
+
+fn print_event(event: ChatEvent) {
+    match event {
+        ChatEvent::Listening { address } => println!("Listening on address: {address}"),
+        ChatEvent::RelayConnected { relay, address } => {
+            println!("Connected to relay {relay} at {address}.")
+        }
+        ChatEvent::RelayDisconnected { relay, retry_in } => {
+            println!(
+                "Lost connection to relay {relay}, reconnecting in {}s.",
+                retry_in.as_secs()
+            )
+        }
+        ChatEvent::ReservationAccepted { relay } => {
+            println!("Relay {relay} accepted our reservation request.")
+        }
+        ChatEvent::ReservationDenied { relay, reason } => {
+            println!("Relay {relay} denied our reservation request: {reason}")
+        }
+        ChatEvent::CircuitDenied { relay, reason } => {
+            println!("Relay {relay} refused to open a circuit: {reason}")
+        }
+        ChatEvent::PeerConnected { peer, endpoint } => {
+            let kind = if endpoint.is_relayed() {
+                ConnectionKind::Relayed
+            } else {
+                ConnectionKind::Direct
+            };
+            println!(
+                "Established new {} connection with {peer} at {endpoint:?}",
+                describe(kind)
+            )
+        }
+        ChatEvent::PeerDisconnected { peer } => println!("Connection with {peer} closed."),
+        ChatEvent::ConnectionChanged { peer, kind } => {
+            println!("Connection with {peer} is {} now.", describe(kind))
+        }
+        ChatEvent::HolePunchSucceeded { peer } => {
+            println!("Hole punch to {peer} succeeded, chatting directly.")
+        }
+        ChatEvent::HolePunchFailed {
+            peer,
+            error,
+            retry_in,
+        } => match retry_in {
+            Some(retry_in) => println!(
+                "Hole punch to {peer} failed: {error}. Retrying in {}s.",
+                retry_in.as_secs()
+            ),
+            None => println!(
+                "Hole punch to {peer} failed: {error}. Staying on the relay, run `hermes doctor` \
+                 to find out why."
+            ),
+        },
+        ChatEvent::CircuitLimited {
+            peer,
+            duration,
+            data_in_bytes,
+        } => {
+            let duration = duration.map_or("no time limit".to_string(), |duration| {
+                format!("{}s", duration.as_secs())
+            });
+            let data = data_in_bytes.map_or("no data limit".to_string(), |bytes| {
+                format!("{bytes} bytes")
+            });
+            println!("The relay limits the circuit to {peer} to {duration} and {data}.")
+        }
+        ChatEvent::CircuitExpiring { peer, remaining } => println!(
+            "The relayed circuit to {peer} closes in {}s unless a hole punch succeeds.",
+            remaining.as_secs()
+        ),
+        ChatEvent::CircuitDataExpiring {
+            peer,
+            remaining_bytes,
+        } => println!(
+            "The relayed circuit to {peer} closes after about {remaining_bytes} more bytes \
+             unless a hole punch succeeds."
+        ),
+        ChatEvent::DialFailed { peer, error } => {
+            println!("Outgoing connection failed to {peer:?}: {error}")
+        }
+        ChatEvent::PeerDiscovered { room, peer, .. } => {
+            println!("Discovered {peer} in room {room}.")
+        }
+        ChatEvent::LanPeerDiscovered { peer, .. } => {
+            println!("Discovered {peer} on the local network.")
+        }
+        ChatEvent::PortMapped { address } => {
+            println!("Router forwards {address} to us, peers can dial it directly.")
+        }
+        ChatEvent::PortMappingExpired { address } => {
+            println!("Router stopped forwarding {address} to us.")
+        }
+        ChatEvent::PortMappingFailed { reason } => println!("Port mapping failed: {reason}"),
+        ChatEvent::UsernameAnnounced { username, peer } => println!("{username} is {peer}"),
+        ChatEvent::UsernameConflict {
+            username,
+            claimed_by,
+            owner,
+        } => println!("{claimed_by} tried to claim {username}, which belongs to {owner}."),
+        ChatEvent::PeerBlacklisted { peer, duration } => println!(
+            "Ignoring {peer} for {}s, it flooded the network.",
+            duration.as_secs()
+        ),
+        ChatEvent::Message {
+            room,
+            id,
+            propagation_source,
+            envelope,
+            verified,
+            ..
+        } => {
+            // Flag names we could not yet check against a username record.
+            let sender = if verified {
+                envelope.sender
+            } else {
+                format!("{}?", envelope.sender)
+            };
+            match envelope.kind {
+                Kind::Text => println!(
+                    "[{room}] {sender}: {} (id {id} via {propagation_source})",
+                    envelope.body
+                ),
+                Kind::Action => println!("[{room}] * {sender} {}", envelope.body),
+                Kind::Unknown(kind) => {
+                    tracing::debug!(%kind, %room, "Not showing message of unknown kind")
                 }
-                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                    propagation_source: peer_id,
-                    message_id: id,
-                    message,
-                })) => {
-                    let msg_str = String::from_utf8_lossy(&message.data);
-                     println!(
-                        "Got message: '{}' with id: {id} from peer: {peer_id}",
-                        msg_str
-                    );
+            }
+        }
+    }
+}
 
-                    // If we receive a peer ID, dial it.
-                    if let Ok(remote_peer_id) = PeerId::from_str(&msg_str) {
-                        // Use the cloned address from outside the loop
-                        let relay_addr_with_circuit = relay_address
-                           .clone()
-                           .with(Protocol::P2pCircuit)
-                           .with(Protocol::P2p(remote_peer_id));
-                       println!(
-                           "Dialing remote peer {} via relay circuit: {}",
-                           remote_peer_id, relay_addr_with_circuit
-                       );
-                       swarm.dial(relay_addr_with_circuit)?;
+/// Runs a `/join`, `/leave`, `/rooms` or `/switch` command typed by the user.
+async fn handle_room_command(
+    node: &mut ChatNode,
+    current_room: &mut Option<String>,
+    line: &str,
+) -> Result<(), NodeStopped> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let room = words.next().map(str::to_string);
+    match (command, room) {
+        ("/join", Some(room)) => match node.join(&room).await {
+            Ok(()) => {
+                println!("Joined room {room}.");
+                *current_room = Some(room);
+            }
+            Err(e) => println!("Cannot join: {e}"),
+        },
+        ("/leave", room) => {
+            let Some(room) = room.or_else(|| current_room.clone()) else {
+                println!("Not in any room.");
+                return Ok(());
+            };
+            match node.leave(&room).await {
+                Ok(()) => {
+                    println!("Left room {room}.");
+                    if current_room.as_ref() == Some(&room) {
+                        *current_room = node.rooms().await?.into_iter().next();
                     }
                 }
-                SwarmEvent::Behaviour(event) => {
-                    // Log other behaviours for debugging
-                    tracing::info!(?event);
-                }
-                _ => {}
+                Err(e) => println!("Cannot leave: {e}"),
+            }
+        }
+        ("/rooms", None) => {
+            for room in node.rooms().await? {
+                let marker = if current_room.as_ref() == Some(&room) {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{marker} {room}");
             }
         }
+        ("/switch", Some(room)) => {
+            if node.rooms().await?.contains(&room) {
+                println!("Sending to room {room}.");
+                *current_room = Some(room);
+            } else {
+                println!("Not a member of room {room}, '/join {room}' first.");
+            }
+        }
+        _ => println!(
+            "Unknown command. Use '/join <room>', '/leave [room]', '/rooms' or '/switch <room>'."
+        ),
     }
+    Ok(())
 }
 
-/// Generates a deterministic Ed25519 keypair from a single byte seed.
-fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
-
-    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
-}
\ No newline at end of file
+fn describe(kind: ConnectionKind) -> &'static str {
+    match kind {
+        ConnectionKind::Relayed => "relayed",
+        ConnectionKind::Direct => "direct",
+    }
+}
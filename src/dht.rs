@@ -0,0 +1,256 @@
+//! Kademlia DHT holding signed username records and signed peer address records.
+//!
+//! Two kinds of records live in the DHT:
+//!
+//! - `/hermes/username/<name>` holds the [`UsernameRecord`] of the name's holder,
+//! - `/hermes/peer/<peer-id>` holds a signed [`PeerRecord`] with the peer's addresses.
+//!
+//! Both are signed by the peer they describe. Nodes only store records that pass
+//! [`accept_inbound`], so nobody can plant a record for someone else's name or PeerId.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libp2p::{
+    core::{PeerRecord, SignedEnvelope},
+    identity,
+    kad::{
+        self,
+        store::{MemoryStore, RecordStore},
+    },
+    Multiaddr, PeerId, StreamProtocol,
+};
+
+use crate::username::{self, RecordError, UsernameRecord};
+
+/// Protocol name of our DHT, kept apart from the public IPFS DHT.
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/hermes/kad/1.0.0");
+
+/// How long a peer address record stays in the DHT without being republished.
+const PEER_RECORD_TTL: Duration = Duration::from_secs(60 * 60);
+
+const USERNAME_PREFIX: &str = "/hermes/username/";
+const PEER_PREFIX: &str = "/hermes/peer/";
+
+/// Builds the Kademlia behaviour. Inbound records are held back for [`accept_inbound`].
+pub fn new_behaviour(local_peer_id: PeerId) -> kad::Behaviour<MemoryStore> {
+    let mut config = kad::Config::default();
+    config.set_protocol_names(vec![PROTOCOL]);
+    config.set_record_filtering(kad::StoreInserts::FilterBoth);
+
+    kad::Behaviour::with_config(local_peer_id, MemoryStore::new(local_peer_id), config)
+}
+
+pub fn username_key(username: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{USERNAME_PREFIX}{username}").into_bytes())
+}
+
+pub fn peer_key(peer: &PeerId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("{PEER_PREFIX}{peer}").into_bytes())
+}
+
+/// The DHT record publishing `record` under its username.
+pub fn username_record(record: &UsernameRecord) -> kad::Record {
+    let mut dht_record = kad::Record::new(username_key(record.username()), record.encode());
+    dht_record.expires = instant_at(record.expires_at());
+    dht_record
+}
+
+/// The DHT record publishing the addresses of the peer of `keypair`.
+pub fn peer_record(
+    keypair: &identity::Keypair,
+    addresses: Vec<Multiaddr>,
+) -> Result<kad::Record, identity::SigningError> {
+    let peer_record = PeerRecord::new(keypair, addresses)?;
+    let mut dht_record = kad::Record::new(
+        peer_key(&peer_record.peer_id()),
+        peer_record.into_signed_envelope().into_protobuf_encoding(),
+    );
+    dht_record.expires = Some(Instant::now() + PEER_RECORD_TTL);
+    Ok(dht_record)
+}
+
+/// Decodes the username record stored under the key of `username`.
+pub fn decode_username_record(
+    username: &str,
+    record: &kad::Record,
+) -> Result<UsernameRecord, RecordError> {
+    let decoded = UsernameRecord::decode(&record.value)?;
+    if decoded.username() != username || record.key != username_key(username) {
+        return Err(RecordError::Malformed);
+    }
+    if decoded.is_expired(SystemTime::now()) {
+        return Err(RecordError::Expired);
+    }
+    Ok(decoded)
+}
+
+/// Decodes the addresses stored under the key of `peer`, if validly signed by `peer`.
+pub fn decode_peer_record(peer: &PeerId, record: &kad::Record) -> Option<Vec<Multiaddr>> {
+    verified_peer_record(peer, record).map(|peer_record| peer_record.addresses().to_vec())
+}
+
+/// Whether to store a record another peer asked us to keep.
+///
+/// Username records must be signed by their holder and may not take over a name held by another
+/// peer. Peer records must be signed by the peer they describe and newer than the one we hold.
+pub fn accept_inbound(store: &MemoryStore, record: &kad::Record) -> bool {
+    let Ok(key) = std::str::from_utf8(record.key.as_ref()) else {
+        return false;
+    };
+
+    if let Some(username) = key.strip_prefix(USERNAME_PREFIX) {
+        let Ok(incoming) = decode_username_record(username, record) else {
+            return false;
+        };
+        let Some(held) = store.get(&record.key) else {
+            return true;
+        };
+        let mut registry = username::Registry::default();
+        let now = SystemTime::now();
+        if let Ok(held) = decode_username_record(username, &held) {
+            let _ = registry.insert(held, now);
+        }
+        return registry.insert(incoming, now).is_ok();
+    }
+
+    if let Some(peer) = key.strip_prefix(PEER_PREFIX) {
+        let Ok(peer) = peer.parse::<PeerId>() else {
+            return false;
+        };
+        let Some(incoming) = verified_peer_record(&peer, record) else {
+            return false;
+        };
+        return store
+            .get(&record.key)
+            .and_then(|held| verified_peer_record(&peer, &held))
+            .is_none_or(|held| incoming.seq() > held.seq());
+    }
+
+    false
+}
+
+/// Stores `record` if [`accept_inbound`] allows it.
+pub fn store_inbound(kad: &mut kad::Behaviour<MemoryStore>, source: PeerId, record: kad::Record) {
+    if !accept_inbound(kad.store_mut(), &record) {
+        tracing::debug!(%source, key=?record.key, "Refused to store DHT record");
+        return;
+    }
+    if let Err(e) = kad.store_mut().put(record) {
+        tracing::debug!(%source, "Failed to store DHT record: {e:?}");
+    }
+}
+
+/// The peer record stored under the key of `peer`, if validly signed by `peer`.
+fn verified_peer_record(peer: &PeerId, record: &kad::Record) -> Option<PeerRecord> {
+    if record.key != peer_key(peer) {
+        return None;
+    }
+    let envelope = SignedEnvelope::from_protobuf_encoding(&record.value).ok()?;
+    let peer_record = PeerRecord::from_signed_envelope(envelope).ok()?;
+    (peer_record.peer_id() == *peer).then_some(peer_record)
+}
+
+/// Converts a unix timestamp in seconds to an [`Instant`], as Kademlia record expiry wants.
+/// `None` if the timestamp is too far in the future to represent.
+fn instant_at(unix_secs: u64) -> Option<Instant> {
+    let at = UNIX_EPOCH.checked_add(Duration::from_secs(unix_secs))?;
+    let remaining = at
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO);
+    Instant::now().checked_add(remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> MemoryStore {
+        MemoryStore::new(PeerId::random())
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn address(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn valid_records_are_accepted() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let store = store();
+
+        let record = username_record(&UsernameRecord::new(&keypair, "alice").unwrap());
+        assert!(accept_inbound(&store, &record));
+        let record = peer_record(&keypair, vec![address("/ip4/192.0.2.1/tcp/4001")]).unwrap();
+        assert!(accept_inbound(&store, &record));
+    }
+
+    #[test]
+    fn records_under_a_mismatched_key_are_refused() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let store = store();
+
+        let mut record = username_record(&UsernameRecord::new(&keypair, "alice").unwrap());
+        record.key = username_key("bob");
+        assert!(!accept_inbound(&store, &record));
+
+        let mut record = peer_record(&keypair, Vec::new()).unwrap();
+        record.key = username_key("alice");
+        assert!(!accept_inbound(&store, &record));
+
+        for key in [&b"/hermes/other/alice"[..], &[0xff, 0xfe][..]] {
+            record.key = kad::RecordKey::new(&key);
+            assert!(!accept_inbound(&store, &record));
+        }
+    }
+
+    #[test]
+    fn records_signed_by_another_peer_are_refused() {
+        let (victim, mallory) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let mut store = store();
+
+        // Mallory's own addresses, planted under the victim's key.
+        let mut record = peer_record(&mallory, vec![address("/ip4/192.0.2.1/tcp/4001")]).unwrap();
+        record.key = peer_key(&victim.public().to_peer_id());
+        assert!(!accept_inbound(&store, &record));
+
+        // A name the victim holds, claimed by Mallory with a newer record.
+        let held = UsernameRecord::new(&victim, "alice").unwrap();
+        store.put(username_record(&held)).unwrap();
+        let claim = UsernameRecord::with_expiry(&mallory, "alice", held.seq() + 1, unix_now() + 60);
+        assert!(!accept_inbound(&store, &username_record(&claim)));
+    }
+
+    #[test]
+    fn expired_username_records_are_refused() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let expired = UsernameRecord::with_expiry(&keypair, "alice", 1, unix_now() - 60);
+        assert!(!accept_inbound(&store(), &username_record(&expired)));
+    }
+
+    #[test]
+    fn only_newer_records_replace_held_ones() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let mut store = store();
+        let now = unix_now();
+
+        let held = UsernameRecord::with_expiry(&keypair, "alice", 2, now + 60);
+        store.put(username_record(&held)).unwrap();
+        let older = UsernameRecord::with_expiry(&keypair, "alice", 1, now + 120);
+        assert!(!accept_inbound(&store, &username_record(&older)));
+        let newer = UsernameRecord::with_expiry(&keypair, "alice", 3, now + 120);
+        assert!(accept_inbound(&store, &username_record(&newer)));
+
+        let held = peer_record(&keypair, Vec::new()).unwrap();
+        store.put(held.clone()).unwrap();
+        assert!(!accept_inbound(&store, &held));
+    }
+}
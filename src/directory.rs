@@ -0,0 +1,450 @@
+//! A directory node that remembers every username announced on the network and answers lookups.
+//!
+//! The directory follows the username topic like any chat node, but keeps the records it accepts
+//! together with the addresses of their holders in an on-disk database, so names announced hours
+//! ago still resolve after a restart. It also serves the DHT, seeding it with every record it holds.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use clap::Args;
+use futures::stream::StreamExt;
+use libp2p::{
+    gossipsub, identify,
+    kad::{self, store::RecordStore},
+    noise, ping, request_response,
+    swarm::NetworkBehaviour,
+    swarm::SwarmEvent,
+    tcp, yamux, Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    behaviour::{self, PROTOCOL_VERSION},
+    cli::IdentityOpts,
+    dht, keystore,
+    lookup::{self, LookupCodec, LookupRequest, LookupResponse, MAX_ADDRESSES},
+    relay::with_peer_id,
+    scoring,
+    username::{self, Registry, UsernameRecord},
+};
+
+#[derive(Debug, Args)]
+pub struct Opts {
+    #[command(flatten)]
+    pub identity: IdentityOpts,
+
+    /// Address to listen on. May be given multiple times.
+    #[arg(
+        long = "listen-address",
+        default_values = ["/ip4/0.0.0.0/tcp/4003", "/ip4/0.0.0.0/udp/4003/quic-v1"]
+    )]
+    pub listen_addresses: Vec<Multiaddr>,
+
+    /// Publicly reachable address of this directory, announced to clients. May be given multiple
+    /// times.
+    #[arg(long = "external-address")]
+    pub external_addresses: Vec<Multiaddr>,
+
+    /// Directory the username database is kept in.
+    #[arg(long, default_value_os_t = keystore::data_dir().join("directory"))]
+    pub database: PathBuf,
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+    gossipsub: gossipsub::Behaviour,
+    lookup: request_response::Behaviour<LookupCodec>,
+    kad: kad::Behaviour<kad::store::MemoryStore>,
+}
+
+/// A username record as stored in the database, keyed by username.
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    record: Vec<u8>,
+    addresses: Vec<Vec<u8>>,
+}
+
+/// The records the directory holds, in memory and on disk.
+struct Directory {
+    db: sled::Db,
+    registry: Registry,
+    addresses: HashMap<PeerId, Vec<Multiaddr>>,
+}
+
+impl Directory {
+    /// Opens the database at `path` and loads every unexpired record, dropping the rest.
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut directory = Directory {
+            db: sled::open(path)?,
+            registry: Registry::default(),
+            addresses: HashMap::new(),
+        };
+
+        let now = SystemTime::now();
+        for entry in directory.db.iter() {
+            let (_, value) = entry?;
+            let Ok(stored) = ciborium::from_reader::<StoredEntry, _>(value.as_ref()) else {
+                continue;
+            };
+            let Ok(record) = UsernameRecord::decode(&stored.record) else {
+                continue;
+            };
+            let peer = record.peer();
+            if directory.registry.insert(record, now).is_err() {
+                continue;
+            }
+            directory.addresses.insert(
+                peer,
+                stored
+                    .addresses
+                    .into_iter()
+                    .filter_map(|address| Multiaddr::try_from(address).ok())
+                    .collect(),
+            );
+        }
+        // Drop what is expired, unreadable or superseded by a newer name of the same peer.
+        for key in directory.db.iter().keys() {
+            let key = key?;
+            let held = std::str::from_utf8(&key)
+                .ok()
+                .and_then(|username| directory.registry.get(username, now));
+            if held.is_none() {
+                directory.db.remove(key)?;
+            }
+        }
+        tracing::info!(
+            records = directory.registry.records(now).count(),
+            "Loaded username directory"
+        );
+
+        Ok(directory)
+    }
+
+    /// Adds `record` and stores it, replacing the name its holder had before.
+    fn insert(&mut self, record: UsernameRecord) -> Result<(), username::RecordError> {
+        let now = SystemTime::now();
+        let peer = record.peer();
+        let previous = self
+            .registry
+            .get_by_peer(&peer, now)
+            .map(|held| held.username().to_string());
+        let username = record.username().to_string();
+        self.registry.insert(record, now)?;
+
+        if let Some(previous) = previous.filter(|previous| *previous != username) {
+            self.remove_stored(&previous);
+        }
+        self.store(&username);
+        Ok(())
+    }
+
+    /// Remembers where `peer` can be reached and stores it with its record.
+    fn set_addresses(&mut self, peer: PeerId, mut addresses: Vec<Multiaddr>) {
+        addresses.truncate(MAX_ADDRESSES);
+        self.addresses.insert(peer, addresses);
+        if let Some(username) = self
+            .registry
+            .get_by_peer(&peer, SystemTime::now())
+            .map(|record| record.username().to_string())
+        {
+            self.store(&username);
+        }
+    }
+
+    fn answer(&self, request: &LookupRequest) -> LookupResponse {
+        match self.registry.get(&request.username, SystemTime::now()) {
+            Some(record) => LookupResponse::Found {
+                record: Box::new(record.clone()),
+                addresses: self
+                    .addresses
+                    .get(&record.peer())
+                    .cloned()
+                    .unwrap_or_default(),
+            },
+            None => LookupResponse::NotFound,
+        }
+    }
+
+    fn store(&self, username: &str) {
+        let Some(record) = self.registry.get(username, SystemTime::now()) else {
+            return;
+        };
+        let entry = StoredEntry {
+            record: record.encode(),
+            addresses: self
+                .addresses
+                .get(&record.peer())
+                .into_iter()
+                .flatten()
+                .map(|address| address.to_vec())
+                .collect(),
+        };
+        let mut value = Vec::new();
+        ciborium::into_writer(&entry, &mut value).expect("writing to a Vec not to fail");
+        if let Err(e) = self.db.insert(username.as_bytes(), value) {
+            tracing::error!(%username, "Failed to store username record: {e}");
+        }
+    }
+
+    fn remove_stored(&self, username: &str) {
+        if let Err(e) = self.db.remove(username.as_bytes()) {
+            tracing::error!(%username, "Failed to remove username record: {e}");
+        }
+    }
+}
+
+pub async fn run(opts: Opts) -> Result<(), Box<dyn Error>> {
+    let mut directory = Directory::open(&opts.database)?;
+
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(opts.identity.keypair()?)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default().nodelay(true),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_behaviour(|key| {
+            Ok(Behaviour {
+                ping: ping::Behaviour::new(ping::Config::new()),
+                identify: identify::Behaviour::new(identify::Config::new(
+                    PROTOCOL_VERSION.to_string(),
+                    key.public(),
+                )),
+                gossipsub: behaviour::new_gossipsub(key, scoring::Profile::default())?,
+                lookup: request_response::Behaviour::new(
+                    [(lookup::PROTOCOL, request_response::ProtocolSupport::Inbound)],
+                    request_response::Config::default(),
+                ),
+                kad: dht::new_behaviour(key.public().to_peer_id()),
+            })
+        })?
+        .build();
+
+    scoring::subscribe(
+        &mut swarm.behaviour_mut().gossipsub,
+        &gossipsub::IdentTopic::new(username::TOPIC),
+        scoring::Profile::default(),
+    )?;
+
+    // Clients rarely confirm our address, so serve the DHT right away.
+    swarm.behaviour_mut().kad.set_mode(Some(kad::Mode::Server));
+    for record in directory.registry.records(SystemTime::now()) {
+        seed_dht(&mut swarm.behaviour_mut().kad, record);
+    }
+
+    for address in opts.listen_addresses {
+        swarm.listen_on(address)?;
+    }
+
+    let local_peer_id = *swarm.local_peer_id();
+    for address in opts.external_addresses {
+        println!(
+            "Clients should use --directory-address {}",
+            with_peer_id(address.clone(), local_peer_id)
+        );
+        swarm.add_external_address(address);
+    }
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                println!("Listening on {}", with_peer_id(address, local_peer_id));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => {
+                if message.topic.as_str() != username::TOPIC {
+                    continue;
+                }
+                let result = UsernameRecord::decode(&message.data).and_then(|record| {
+                    let (username, peer) = (record.username().to_string(), record.peer());
+                    directory.insert(record.clone())?;
+                    seed_dht(&mut swarm.behaviour_mut().kad, &record);
+                    tracing::info!(%username, %peer, "Stored username record");
+                    Ok(())
+                });
+                if let Err(e) = &result {
+                    tracing::debug!(source=%propagation_source, "Dropped username record: {e}");
+                }
+                let _ = swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .report_message_validation_result(
+                        &message_id,
+                        &propagation_source,
+                        username::acceptance(result.as_ref().copied()),
+                    );
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Lookup(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            })) => {
+                let response = directory.answer(&request);
+                tracing::debug!(%peer, username=%request.username, ?response, "Answering lookup");
+                let _ = swarm
+                    .behaviour_mut()
+                    .lookup
+                    .send_response(channel, response);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => {
+                if info.protocols.contains(&dht::PROTOCOL) {
+                    for address in &info.listen_addrs {
+                        swarm
+                            .behaviour_mut()
+                            .kad
+                            .add_address(&peer_id, address.clone());
+                    }
+                }
+                directory.set_addresses(peer_id, info.listen_addrs);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kad(kad::Event::InboundRequest {
+                request:
+                    kad::InboundRequest::PutRecord {
+                        source,
+                        record: Some(record),
+                        ..
+                    },
+            })) => {
+                dht::store_inbound(&mut swarm.behaviour_mut().kad, source, record);
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                tracing::info!(peer=%peer_id, ?endpoint, "Established new connection");
+                swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .remove_explicit_peer(&peer_id);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Stores `record` in our part of the DHT, from where Kademlia replicates it to the closest peers.
+fn seed_dht(kad: &mut kad::Behaviour<kad::store::MemoryStore>, record: &UsernameRecord) {
+    let record = dht::username_record(record);
+    if !dht::accept_inbound(kad.store_mut(), &record) {
+        return;
+    }
+    if let Err(e) = kad.store_mut().put(record) {
+        tracing::debug!("Failed to store DHT record: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity;
+
+    fn temporary() -> Directory {
+        Directory {
+            db: sled::Config::new().temporary(true).open().unwrap(),
+            registry: Registry::default(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    fn lookup(directory: &Directory, username: &str) -> LookupResponse {
+        directory.answer(&LookupRequest {
+            username: username.to_string(),
+        })
+    }
+
+    fn holder(directory: &Directory, username: &str) -> Option<PeerId> {
+        match lookup(directory, username) {
+            LookupResponse::Found { record, .. } => Some(record.peer()),
+            LookupResponse::NotFound => None,
+        }
+    }
+
+    #[test]
+    fn first_claim_of_a_name_wins() {
+        let (alice, mallory) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let mut directory = temporary();
+
+        directory
+            .insert(UsernameRecord::new(&alice, "alice").unwrap())
+            .unwrap();
+        assert!(matches!(
+            directory.insert(UsernameRecord::new(&mallory, "alice").unwrap()),
+            Err(username::RecordError::Taken { .. })
+        ));
+        assert_eq!(
+            holder(&directory, "alice"),
+            Some(alice.public().to_peer_id())
+        );
+
+        // Renaming frees the old name, in memory and on disk.
+        directory
+            .insert(UsernameRecord::new(&alice, "alicia").unwrap())
+            .unwrap();
+        assert_eq!(holder(&directory, "alice"), None);
+        assert!(!directory.db.contains_key("alice").unwrap());
+        directory
+            .insert(UsernameRecord::new(&mallory, "alice").unwrap())
+            .unwrap();
+        assert_eq!(
+            holder(&directory, "alice"),
+            Some(mallory.public().to_peer_id())
+        );
+    }
+
+    #[test]
+    fn records_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("hermes-directory-{}", PeerId::random()));
+        let (alice, mallory) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let address: Multiaddr = "/ip4/192.0.2.1/tcp/4001".parse().unwrap();
+
+        let mut directory = Directory::open(&path).unwrap();
+        directory
+            .insert(UsernameRecord::new(&alice, "alice").unwrap())
+            .unwrap();
+        directory.set_addresses(alice.public().to_peer_id(), vec![address.clone()]);
+        drop(directory);
+
+        let mut directory = Directory::open(&path).unwrap();
+        let response = lookup(&directory, "alice");
+        let taken = directory.insert(UsernameRecord::new(&mallory, "alice").unwrap());
+        drop(directory);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        match response {
+            LookupResponse::Found { record, addresses } => {
+                assert_eq!(record.peer(), alice.public().to_peer_id());
+                assert_eq!(addresses, vec![address]);
+            }
+            LookupResponse::NotFound => panic!("alice was forgotten"),
+        }
+        assert!(matches!(taken, Err(username::RecordError::Taken { .. })));
+    }
+}
@@ -0,0 +1,275 @@
+//! Diagnoses why peers cannot connect: reachability, observed addresses, the relay reservation
+//! and the odds of hole punching.
+//!
+//! The node connects to the relay like a chat client, asks it to dial back via AutoNAT and
+//! prints a report once everything is answered or the timeout runs out.
+
+use std::{error::Error, time::Duration};
+
+use clap::Args;
+use futures::stream::StreamExt;
+use libp2p::{
+    autonat, core::multiaddr::Protocol, identify, relay, request_response, swarm::SwarmEvent, upnp,
+    Multiaddr, PeerId,
+};
+
+use crate::{
+    access::{InviteResponse, InviteToken},
+    behaviour::{self, BehaviourEvent, SwarmOptions},
+    cli::{IdentityOpts, TransportOpts},
+};
+
+#[derive(Debug, Args)]
+pub struct Opts {
+    #[command(flatten)]
+    pub identity: IdentityOpts,
+
+    /// The listening address of the relay server, ending in `/p2p/<peer-id>`.
+    #[arg(long)]
+    pub relay_address: Multiaddr,
+
+    /// Invite token issued by the relay operator, needed if the relay restricts access.
+    #[arg(long)]
+    pub invite_token: Option<InviteToken>,
+
+    /// Also check whether the router forwards our ports via UPnP.
+    #[arg(long)]
+    pub upnp: bool,
+
+    /// How long to wait for the relay and AutoNAT to answer.
+    #[arg(long, default_value_t = 45)]
+    pub timeout_secs: u64,
+
+    #[command(flatten)]
+    pub transport: TransportOpts,
+}
+
+/// What the diagnosis found out.
+#[derive(Debug, Default)]
+struct Report {
+    listen_addresses: Vec<Multiaddr>,
+    relay: Option<PeerId>,
+    /// Our addresses as the relay saw them, reported via identify.
+    observed_addresses: Vec<Multiaddr>,
+    /// Observed addresses AutoNAT confirmed to be reachable.
+    confirmed_addresses: Vec<Multiaddr>,
+    nat_status: Option<autonat::NatStatus>,
+    /// Why the last AutoNAT probe failed, if it did.
+    probe_error: Option<String>,
+    reservation: Option<Result<(), String>>,
+    /// Whether UPnP was asked for, and what it mapped.
+    upnp: bool,
+    port_mapping: Option<Result<Multiaddr, String>>,
+}
+
+impl Report {
+    fn is_complete(&self) -> bool {
+        self.reservation.is_some()
+            && (!self.upnp || self.port_mapping.is_some())
+            && !matches!(self.nat_status, None | Some(autonat::NatStatus::Unknown))
+    }
+
+    fn print(&self) {
+        println!("Listen addresses:");
+        print_addresses(&self.listen_addresses);
+
+        match self.relay {
+            Some(relay) => println!("Relay: connected to {relay}"),
+            None => println!("Relay: not reachable"),
+        }
+
+        println!("Observed addresses (identify):");
+        print_addresses(&self.observed_addresses);
+        println!("Confirmed external addresses (AutoNAT):");
+        print_addresses(&self.confirmed_addresses);
+
+        match &self.nat_status {
+            Some(autonat::NatStatus::Public(address)) => {
+                println!("Reachability: public at {address}")
+            }
+            Some(autonat::NatStatus::Private) => {
+                println!("Reachability: private, behind a NAT or a firewall")
+            }
+            _ => match &self.probe_error {
+                Some(error) => println!("Reachability: unknown, AutoNAT probe failed: {error}"),
+                None => println!("Reachability: unknown, AutoNAT got no answer"),
+            },
+        }
+
+        match &self.reservation {
+            Some(Ok(())) => println!("Relay reservation: active"),
+            Some(Err(reason)) => println!("Relay reservation: denied, {reason}"),
+            None => println!("Relay reservation: no answer"),
+        }
+
+        match &self.port_mapping {
+            _ if !self.upnp => {}
+            Some(Ok(address)) => println!("UPnP: router forwards {address}"),
+            Some(Err(reason)) => println!("UPnP: {reason}"),
+            None => println!("UPnP: no answer"),
+        }
+
+        println!("Hole punching: {}", self.hole_punch_verdict());
+    }
+
+    /// Estimates whether DCUtR will succeed from what we know about our NAT.
+    fn hole_punch_verdict(&self) -> &'static str {
+        if matches!(self.nat_status, Some(autonat::NatStatus::Public(_))) {
+            return "not needed, peers can dial this node directly.";
+        }
+        if !matches!(self.reservation, Some(Ok(()))) {
+            return "unlikely, without a relay reservation peers cannot reach this node to \
+                    coordinate a hole punch.";
+        }
+        if self.observed_addresses.is_empty() {
+            return "unlikely, the relay did not tell us the address it sees us at.";
+        }
+        let listen_ports: Vec<u16> = self.listen_addresses.iter().filter_map(port).collect();
+        if self
+            .observed_addresses
+            .iter()
+            .filter_map(port)
+            .any(|observed| listen_ports.contains(&observed))
+        {
+            return "likely, the NAT keeps our local port, so remote peers can use the address \
+                    the relay observed. A firewall may still drop their packets.";
+        }
+        "uncertain, the NAT changes our port. Hole punching fails behind symmetric NATs, which \
+         pick a new port for every destination."
+    }
+}
+
+fn print_addresses(addresses: &[Multiaddr]) {
+    if addresses.is_empty() {
+        println!("  none");
+    }
+    for address in addresses {
+        println!("  {address}");
+    }
+}
+
+/// The TCP or UDP port of `address`.
+fn port(address: &Multiaddr) -> Option<u16> {
+    address.iter().find_map(|protocol| match protocol {
+        Protocol::Tcp(port) | Protocol::Udp(port) => Some(port),
+        _ => None,
+    })
+}
+
+pub async fn run(opts: Opts) -> Result<(), Box<dyn Error>> {
+    let mut swarm = behaviour::build_swarm(
+        opts.identity.keypair()?,
+        SwarmOptions {
+            upnp: opts.upnp,
+            transports: opts.transport.transports(),
+            ..Default::default()
+        },
+    )?;
+    for address in opts.transport.listen_addresses() {
+        swarm.listen_on(address)?;
+    }
+
+    println!("Connecting to relay at {}", opts.relay_address);
+    swarm.dial(opts.relay_address.clone())?;
+
+    let mut report = Report {
+        upnp: opts.upnp,
+        ..Default::default()
+    };
+    let mut invite_token = opts.invite_token;
+    let mut timeout = futures_timer::Delay::new(Duration::from_secs(opts.timeout_secs));
+
+    while !report.is_complete() {
+        let event = tokio::select! {
+            event = swarm.select_next_some() => event,
+            _ = &mut timeout => break,
+        };
+        match event {
+            SwarmEvent::NewListenAddr { address, .. }
+                if !address.iter().any(|p| p == Protocol::P2pCircuit) =>
+            {
+                report.listen_addresses.push(address);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) if report.relay.is_none() => {
+                tracing::info!(address=%info.observed_addr, "Relay told us our observed address");
+                report.relay = Some(peer_id);
+                report.observed_addresses.push(info.observed_addr);
+                swarm
+                    .behaviour_mut()
+                    .autonat
+                    .add_server(peer_id, Some(opts.relay_address.clone()));
+                match invite_token.take() {
+                    Some(token) => {
+                        swarm.behaviour_mut().invite.send_request(&peer_id, token);
+                    }
+                    None => {
+                        swarm.listen_on(opts.relay_address.clone().with(Protocol::P2pCircuit))?;
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Invite(request_response::Event::Message {
+                message: request_response::Message::Response { response, .. },
+                ..
+            })) => match response {
+                InviteResponse::Accepted => {
+                    swarm.listen_on(opts.relay_address.clone().with(Protocol::P2pCircuit))?;
+                }
+                InviteResponse::Rejected(reason) => {
+                    report.reservation = Some(Err(format!("invite token rejected: {reason}")));
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Invite(
+                request_response::Event::OutboundFailure { error, .. },
+            )) => {
+                report.reservation = Some(Err(format!("failed to redeem invite token: {error}")));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted { .. },
+            )) => {
+                report.reservation = Some(Ok(()));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqFailed { error, .. },
+            )) => {
+                report.reservation = Some(Err(error.to_string()));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged {
+                new,
+                ..
+            })) => {
+                tracing::info!(reachability=?new, "AutoNAT updated our reachability");
+                report.nat_status = Some(new);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::OutboundProbe(
+                autonat::OutboundProbeEvent::Error { error, .. },
+            ))) => {
+                report.probe_error = Some(format!("{error:?}"));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(event)) => match event {
+                upnp::Event::NewExternalAddr(address) => report.port_mapping = Some(Ok(address)),
+                upnp::Event::GatewayNotFound => {
+                    report.port_mapping = Some(Err("no gateway found".to_string()))
+                }
+                upnp::Event::NonRoutableGateway => {
+                    report.port_mapping = Some(Err("the gateway has no public address".to_string()))
+                }
+                upnp::Event::ExpiredExternalAddr(_) => {}
+            },
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                tracing::info!(peer=?peer_id, "Outgoing connection failed: {error}");
+            }
+            _ => {}
+        }
+    }
+
+    // AutoNAT adds the observed addresses it confirmed to the swarm's external addresses.
+    report.confirmed_addresses = swarm.external_addresses().cloned().collect();
+
+    println!();
+    report.print();
+
+    Ok(())
+}
@@ -0,0 +1,387 @@
+//! The versioned envelope every chat message travels in.
+//!
+//! An envelope is a CBOR map with named fields. Decoders skip fields they do not know, so later
+//! versions can add fields without breaking older clients. An envelope of a newer [`VERSION`] is
+//! read as far as we understand it, with its [`Kind`] unknown so it is never misread as text. Only
+//! if even the fields we know are missing is it reported as unsupported.
+//!
+//! Every envelope carries a sequence number that counts up per sender. A message's id is a
+//! SHA-256 hash of the sender's peer ID and that number, so two senders writing the same text, or
+//! one sender repeating it, still produce distinct messages that gossipsub does not drop as
+//! duplicates.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{gossipsub, PeerId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Version of the envelope format we write, and the newest we read.
+pub const VERSION: u32 = 2;
+
+/// Prefixes the hashed input of chat message ids.
+const MESSAGE_ID_DOMAIN: &[u8] = b"hermes/message-id/1";
+
+/// Prefixes the hashed input of ids of gossip messages that are not chat messages.
+const GOSSIP_ID_DOMAIN: &[u8] = b"hermes/gossip-id/1";
+
+/// What a message is, so control messages never get mistaken for text a user typed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    /// Text a user typed.
+    Text,
+    /// An action a user describes in the third person, sent with `/me`.
+    Action,
+    /// A kind introduced by a later version. Kept so the message can still be forwarded.
+    Unknown(String),
+}
+
+impl Kind {
+    fn as_str(&self) -> &str {
+        match self {
+            Kind::Text => "text",
+            Kind::Action => "action",
+            Kind::Unknown(kind) => kind,
+        }
+    }
+
+    fn from_wire(kind: String) -> Self {
+        match kind.as_str() {
+            "text" => Kind::Text,
+            "action" => Kind::Action,
+            _ => Kind::Unknown(kind),
+        }
+    }
+}
+
+/// Identifies a message independently of its content, so repeating a text is a new message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageId([u8; 32]);
+
+impl MessageId {
+    /// The id of the message `sender` numbered `seq`.
+    pub fn new(sender: &PeerId, seq: u64) -> Self {
+        let sender = sender.to_bytes();
+        let mut hasher = Sha256::new();
+        hasher.update(MESSAGE_ID_DOMAIN);
+        // Length-prefixed, so no sender's bytes can run into the sequence number.
+        hasher.update((sender.len() as u32).to_be_bytes());
+        hasher.update(&sender);
+        hasher.update(seq.to_be_bytes());
+        Self(hasher.finalize().into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// A chat message with everything needed to display it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub kind: Kind,
+    /// Counts up with every message of the sender.
+    pub seq: u64,
+    /// Username the sender claims. Whether it holds the name is checked separately.
+    pub sender: String,
+    /// When the sender wrote the message, by its clock.
+    pub timestamp: SystemTime,
+    pub room: String,
+    pub body: String,
+}
+
+/// Just the version of an envelope, which every version of the format has.
+#[derive(Deserialize)]
+struct WireVersion {
+    version: u32,
+}
+
+/// The encoding of [`Envelope`] on the wire.
+#[derive(Serialize, Deserialize)]
+struct WireEnvelope {
+    version: u32,
+    kind: String,
+    seq: u64,
+    sender: String,
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+    room: String,
+    body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not a CBOR map with the fields of an envelope.
+    Malformed(String),
+    /// Written by a newer client in a format we cannot read at all.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Malformed(e) => write!(f, "malformed envelope: {e}"),
+            DecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "envelope version {version} is newer than the supported version {VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Envelope {
+    /// A new message of `kind` from `sender` in `room`, written now. `seq` must be higher than
+    /// that of any earlier message of the sender.
+    pub fn new(kind: Kind, seq: u64, sender: &str, room: &str, body: String) -> Self {
+        Self {
+            kind,
+            seq,
+            sender: sender.to_string(),
+            timestamp: SystemTime::now(),
+            room: room.to_string(),
+            body,
+        }
+    }
+
+    /// The id of this message, sent by the peer `source`.
+    pub fn id(&self, source: &PeerId) -> MessageId {
+        MessageId::new(source, self.seq)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let wire: WireEnvelope = match ciborium::from_reader(bytes) {
+            Ok(wire) => wire,
+            Err(e) => {
+                return Err(match ciborium::from_reader::<WireVersion, _>(bytes) {
+                    Ok(WireVersion { version }) if version > VERSION => {
+                        DecodeError::UnsupportedVersion(version)
+                    }
+                    _ => DecodeError::Malformed(e.to_string()),
+                });
+            }
+        };
+        // A newer version may have changed what a kind means.
+        let kind = if wire.version > VERSION {
+            Kind::Unknown(wire.kind)
+        } else {
+            Kind::from_wire(wire.kind)
+        };
+
+        Ok(Self {
+            kind,
+            seq: wire.seq,
+            sender: wire.sender,
+            timestamp: UNIX_EPOCH + Duration::from_millis(wire.timestamp),
+            room: wire.room,
+            body: wire.body,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let wire = WireEnvelope {
+            version: VERSION,
+            kind: self.kind.as_str().to_string(),
+            seq: self.seq,
+            sender: self.sender.clone(),
+            timestamp: self
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            room: self.room.clone(),
+            body: self.body.clone(),
+        };
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&wire, &mut bytes).expect("writing to a Vec not to fail");
+        bytes
+    }
+}
+
+/// The gossipsub id of `message`, which is what gossipsub deduplicates messages by.
+///
+/// Chat messages get the id of their envelope. Anything else is identified by its source, the
+/// sequence number gossipsub gave it and its topic. Both are only trusted because strict
+/// validation makes gossipsub check the source's signature over them.
+pub fn gossip_message_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    if let (Some(source), Ok(envelope)) = (message.source, Envelope::decode(&message.data)) {
+        return gossipsub::MessageId::from(envelope.id(&source).to_string());
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(GOSSIP_ID_DOMAIN);
+    let source = message
+        .source
+        .map(|source| source.to_bytes())
+        .unwrap_or_default();
+    hasher.update((source.len() as u32).to_be_bytes());
+    hasher.update(&source);
+    hasher.update(message.sequence_number.unwrap_or_default().to_be_bytes());
+    let topic = message.topic.as_str().as_bytes();
+    hasher.update((topic.len() as u32).to_be_bytes());
+    hasher.update(topic);
+    // All that tells apart messages without a source or sequence number.
+    hasher.update(&message.data);
+    gossipsub::MessageId::from(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn gossip(source: PeerId, sequence_number: u64, data: Vec<u8>) -> gossipsub::Message {
+        gossipsub::Message {
+            source: Some(source),
+            data,
+            sequence_number: Some(sequence_number),
+            topic: gossipsub::TopicHash::from_raw("hermes/room/general"),
+        }
+    }
+
+    /// Delivers `messages` the way gossipsub does, dropping those whose id it has seen before.
+    fn deliver(messages: &[gossipsub::Message]) -> Vec<&gossipsub::Message> {
+        let mut seen = HashSet::new();
+        messages
+            .iter()
+            .filter(|message| seen.insert(gossip_message_id(message)))
+            .collect()
+    }
+
+    fn text(seq: u64, sender: &str, body: &str) -> Vec<u8> {
+        Envelope::new(Kind::Text, seq, sender, "general", body.to_string()).encode()
+    }
+
+    #[test]
+    fn identical_texts_from_different_senders_are_all_delivered() {
+        // Even with equal sequence numbers, as senders that started at the same time have.
+        let messages: Vec<_> = (0..3)
+            .map(|_| gossip(PeerId::random(), 1, text(1, "alice", "hello")))
+            .collect();
+
+        assert_eq!(deliver(&messages).len(), messages.len());
+    }
+
+    #[test]
+    fn repeated_texts_from_one_sender_are_all_delivered() {
+        let sender = PeerId::random();
+        let messages: Vec<_> = (0..3)
+            .map(|seq| gossip(sender, seq, text(seq, "alice", "hello")))
+            .collect();
+
+        assert_eq!(deliver(&messages).len(), messages.len());
+    }
+
+    #[test]
+    fn identical_records_from_different_senders_are_all_delivered() {
+        // Payloads other than envelopes, such as username records.
+        let messages: Vec<_> = (0..3)
+            .map(|_| gossip(PeerId::random(), 1, b"record".to_vec()))
+            .collect();
+
+        assert_eq!(deliver(&messages).len(), messages.len());
+    }
+
+    #[test]
+    fn the_same_message_is_delivered_once() {
+        let message = gossip(PeerId::random(), 7, text(7, "alice", "hello"));
+
+        assert_eq!(deliver(&[message.clone(), message]).len(), 1);
+    }
+
+    #[test]
+    fn gossip_id_is_the_envelope_id() {
+        let sender = PeerId::random();
+        let envelope = Envelope::new(Kind::Text, 5, "alice", "general", "hi".to_string());
+        let message = gossip(sender, 99, envelope.encode());
+
+        assert_eq!(
+            gossip_message_id(&message),
+            gossipsub::MessageId::from(envelope.id(&sender).to_string())
+        );
+    }
+
+    #[test]
+    fn message_id_is_stable() {
+        let sender: PeerId = "12D3KooWD3eckifWpRn9wQpMG9R9hX3sD158z7EqHWmweQAJU5SA"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            MessageId::new(&sender, 42).to_string(),
+            "ea5cc68ea82ec29558168d3fe7f157cb633681e52d47c7c0e1ec96631ded515a"
+        );
+    }
+
+    #[test]
+    fn envelope_round_trips() {
+        let envelope = Envelope {
+            kind: Kind::Action,
+            seq: 3,
+            sender: "alice".to_string(),
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_700_000_000_000),
+            room: "general".to_string(),
+            body: "waves".to_string(),
+        };
+
+        assert_eq!(Envelope::decode(&envelope.encode()), Ok(envelope));
+    }
+
+    #[test]
+    fn newer_version_decodes_as_unknown_kind() {
+        let wire = WireEnvelope {
+            version: VERSION + 1,
+            kind: "text".to_string(),
+            seq: 3,
+            sender: "alice".to_string(),
+            timestamp: 1_700_000_000_000,
+            room: "general".to_string(),
+            body: "hi".to_string(),
+        };
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&wire, &mut bytes).unwrap();
+
+        let envelope = Envelope::decode(&bytes).unwrap();
+        assert_eq!(envelope.kind, Kind::Unknown("text".to_string()));
+        assert_eq!(envelope.sender, "alice");
+        assert_eq!(envelope.room, "general");
+    }
+
+    #[test]
+    fn unreadable_newer_version_is_unsupported() {
+        #[derive(Serialize)]
+        struct Future {
+            version: u32,
+            payload: Vec<u8>,
+        }
+        let mut bytes = Vec::new();
+        ciborium::into_writer(
+            &Future {
+                version: VERSION + 1,
+                payload: vec![1, 2, 3],
+            },
+            &mut bytes,
+        )
+        .unwrap();
+
+        assert_eq!(
+            Envelope::decode(&bytes),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+        assert!(matches!(
+            Envelope::decode(b"garbage"),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+//! The `identity` subcommand for inspecting, moving and backing up the local identity.
+
+use std::{error::Error, path::PathBuf};
+
+use clap::Subcommand;
+
+use crate::{keystore, mnemonic};
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Print the peer id of the local identity.
+    Show {
+        /// File holding this node's identity keypair.
+        #[arg(long, default_value_os_t = keystore::default_identity_path())]
+        identity_file: PathBuf,
+    },
+    /// Write the local identity to a file for moving it to another machine.
+    Export {
+        /// File holding this node's identity keypair.
+        #[arg(long, default_value_os_t = keystore::default_identity_path())]
+        identity_file: PathBuf,
+
+        /// Write the bare libp2p protobuf keypair encoding instead of encrypting the export.
+        #[arg(long)]
+        unencrypted: bool,
+
+        /// Destination of the exported keypair.
+        out: PathBuf,
+    },
+    /// Encrypt a keypair exported on another machine and use it as the local identity.
+    Import {
+        /// File holding this node's identity keypair. Must not exist yet.
+        #[arg(long, default_value_os_t = keystore::default_identity_path())]
+        identity_file: PathBuf,
+
+        /// Exported keypair, either encrypted or in the libp2p protobuf keypair encoding.
+        input: PathBuf,
+    },
+    /// Print the recovery phrase of the local identity for a paper backup.
+    Backup {
+        /// File holding this node's identity keypair.
+        #[arg(long, default_value_os_t = keystore::default_identity_path())]
+        identity_file: PathBuf,
+    },
+    /// Restore the local identity from a recovery phrase.
+    Recover {
+        /// File holding this node's identity keypair. Must not exist yet.
+        #[arg(long, default_value_os_t = keystore::default_identity_path())]
+        identity_file: PathBuf,
+    },
+}
+
+pub fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Show { identity_file } => {
+            let keypair = keystore::read_keypair_file(&identity_file)?;
+            println!("{}", keypair.public().to_peer_id());
+        }
+        Command::Export {
+            identity_file,
+            unencrypted,
+            out,
+        } => {
+            let keypair = keystore::read_keypair_file(&identity_file)?;
+            if unencrypted {
+                eprintln!("Warning: {} holds an unencrypted private key.", out.display());
+                keystore::export(&out, &keypair, None)?;
+            } else {
+                let passphrase = keystore::read_passphrase("Export passphrase: ", true)?;
+                keystore::export(&out, &keypair, Some(passphrase.as_str()))?;
+            }
+            println!("Exported {} to {}", keypair.public().to_peer_id(), out.display());
+        }
+        Command::Import {
+            identity_file,
+            input,
+        } => {
+            let keypair = keystore::read_keypair_file(&input)?;
+            let passphrase = keystore::read_passphrase("New identity passphrase: ", true)?;
+            keystore::store(&identity_file, &keypair, &passphrase)?;
+            println!(
+                "Imported {} into {}",
+                keypair.public().to_peer_id(),
+                identity_file.display()
+            );
+        }
+        Command::Backup { identity_file } => {
+            let keypair = keystore::read_keypair_file(&identity_file)?;
+            let phrase = mnemonic::to_phrase(&keypair)?;
+            println!("Recovery phrase for {}:", keypair.public().to_peer_id());
+            println!();
+            for (i, word) in phrase.split(' ').enumerate() {
+                println!("{:>2}. {word}", i + 1);
+            }
+            println!();
+            println!("Anyone holding these words can impersonate you. Keep them offline.");
+        }
+        Command::Recover { identity_file } => {
+            let phrase = zeroize::Zeroizing::new(rpassword::prompt_password("Recovery phrase: ")?);
+            let keypair = mnemonic::from_phrase(&phrase)?;
+            let passphrase = keystore::read_passphrase("New identity passphrase: ", true)?;
+            keystore::store(&identity_file, &keypair, &passphrase)?;
+            println!(
+                "Recovered {} into {}",
+                keypair.public().to_peer_id(),
+                identity_file.display()
+            );
+        }
+    }
+
+    Ok(())
+}
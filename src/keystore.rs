@@ -0,0 +1,401 @@
+//! On-disk storage of the node's identity keypair.
+//!
+//! On first start a random Ed25519 keypair is generated and written to the identity file. Every
+//! later start loads the same keypair, so the node keeps its [`PeerId`](libp2p::PeerId) across
+//! restarts.
+//!
+//! Identity files are encrypted at rest. The libp2p protobuf keypair encoding is sealed with
+//! ChaCha20-Poly1305 under a key derived from a passphrase with Argon2id:
+//!
+//! ```text
+//! magic "HERMESK1" | m_cost u32 | t_cost u32 | p_cost u32 | salt [16] | nonce [12] | ciphertext
+//! ```
+//!
+//! All integers are little endian. The header up to and including the nonce is authenticated as
+//! associated data, so tampering with the KDF parameters is detected.
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, OsRng, Payload},
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+};
+use libp2p::identity;
+use zeroize::Zeroizing;
+
+/// Name of the identity file inside the default configuration directory.
+const IDENTITY_FILE_NAME: &str = "identity.key";
+
+/// Environment variable read before prompting for the identity passphrase.
+pub const PASSPHRASE_ENV: &str = "HERMES_PASSPHRASE";
+
+const MAGIC: &[u8; 8] = b"HERMESK1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 3 * 4 + SALT_LEN + NONCE_LEN;
+
+/// Argon2id memory cost in KiB used for newly written files.
+const KDF_M_COST: u32 = 64 * 1024;
+/// Argon2id iterations used for newly written files.
+const KDF_T_COST: u32 = 3;
+/// Argon2id parallelism used for newly written files.
+const KDF_P_COST: u32 = 1;
+
+/// Returns the default location of the identity file, `$HOME/.hermes/identity.key`.
+///
+/// Falls back to the current directory when `$HOME` is not set.
+pub fn default_identity_path() -> PathBuf {
+    data_dir().join(IDENTITY_FILE_NAME)
+}
+
+/// Directory hermes keeps its state in, `$HOME/.hermes`.
+pub fn data_dir() -> PathBuf {
+    env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".hermes"))
+        .unwrap_or_default()
+}
+
+/// Picks the identity for this run.
+///
+/// `secret_key_seed` is only set in `--insecure-test-identity` mode; otherwise the keypair stored
+/// at `path` is loaded, or generated and stored if the file does not exist yet.
+pub fn resolve_identity(
+    path: &Path,
+    secret_key_seed: Option<u8>,
+) -> io::Result<identity::Keypair> {
+    match secret_key_seed {
+        Some(seed) => {
+            tracing::warn!("Using insecure deterministic test identity derived from seed {seed}");
+            Ok(insecure_test_identity(seed))
+        }
+        None => load_or_generate(path),
+    }
+}
+
+/// Loads the keypair stored at `path`, generating and storing a fresh one if the file is missing.
+///
+/// The passphrase is taken from [`PASSPHRASE_ENV`] or prompted for on the terminal.
+pub fn load_or_generate(path: &Path) -> io::Result<identity::Keypair> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let keypair = if is_encrypted(&bytes) {
+                let passphrase = read_passphrase("Identity passphrase: ", false)?;
+                decrypt(&bytes, &passphrase)?
+            } else {
+                tracing::warn!(
+                    path=%path.display(),
+                    "Identity file is not encrypted, re-import it with `identity import`"
+                );
+                decode(&bytes)?
+            };
+            tracing::info!(path=%path.display(), "Loaded identity");
+            Ok(keypair)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = identity::Keypair::generate_ed25519();
+            let passphrase = read_passphrase("New identity passphrase: ", true)?;
+            store(path, &keypair, &passphrase)?;
+            tracing::info!(path=%path.display(), "Generated new identity");
+            Ok(keypair)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Encrypts `keypair` with `passphrase` and writes it to a new file at `path` that only the
+/// current user can read.
+///
+/// Refuses to overwrite an existing file so an identity is never lost by accident.
+pub fn store(path: &Path, keypair: &identity::Keypair, passphrase: &str) -> io::Result<()> {
+    write_private(path, &encrypt(keypair, passphrase)?)
+}
+
+/// Reads a keypair file as written by [`store`] or [`export`], prompting for the passphrase if
+/// the file is encrypted.
+pub fn read_keypair_file(path: &Path) -> io::Result<identity::Keypair> {
+    let bytes = fs::read(path)?;
+    if is_encrypted(&bytes) {
+        let passphrase = read_passphrase(&format!("Passphrase for {}: ", path.display()), false)?;
+        decrypt(&bytes, &passphrase)
+    } else {
+        decode(&bytes)
+    }
+}
+
+/// Writes `keypair` to a new private file at `path` for moving it to another machine.
+///
+/// With a passphrase the file uses the same encrypted format as identity files; without one it
+/// holds the bare libp2p protobuf keypair encoding.
+pub fn export(path: &Path, keypair: &identity::Keypair, passphrase: Option<&str>) -> io::Result<()> {
+    match passphrase {
+        Some(passphrase) => write_private(path, &encrypt(keypair, passphrase)?),
+        None => write_private(path, &encode(keypair)?),
+    }
+}
+
+/// Returns the passphrase from [`PASSPHRASE_ENV`], or prompts for it on the terminal.
+///
+/// With `confirm` the passphrase has to be typed twice and may not be empty.
+pub fn read_passphrase(prompt: &str, confirm: bool) -> io::Result<Zeroizing<String>> {
+    if let Some(passphrase) = env::var_os(PASSPHRASE_ENV) {
+        return passphrase.into_string().map(Zeroizing::new).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{PASSPHRASE_ENV} is not valid UTF-8"),
+            )
+        });
+    }
+
+    let passphrase = Zeroizing::new(rpassword::prompt_password(prompt)?);
+    if confirm {
+        if passphrase.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Passphrase may not be empty",
+            ));
+        }
+        let repeated = Zeroizing::new(rpassword::prompt_password("Repeat passphrase: ")?);
+        if passphrase != repeated {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Passphrases do not match",
+            ));
+        }
+    }
+
+    Ok(passphrase)
+}
+
+/// Generates a deterministic Ed25519 keypair from a single byte seed.
+///
+/// Only 256 such identities exist and anyone can recreate their private keys, so this must never
+/// be used outside of local tests.
+pub fn insecure_test_identity(secret_key_seed: u8) -> identity::Keypair {
+    let mut bytes = [0u8; 32];
+    bytes[0] = secret_key_seed;
+
+    identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+}
+
+fn encode(keypair: &identity::Keypair) -> io::Result<Vec<u8>> {
+    keypair
+        .to_protobuf_encoding()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn decode(bytes: &[u8]) -> io::Result<identity::Keypair> {
+    identity::Keypair::from_protobuf_encoding(bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn encrypt(keypair: &identity::Keypair, passphrase: &str) -> io::Result<Vec<u8>> {
+    let plaintext = Zeroizing::new(encode(keypair)?);
+
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut out = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&KDF_M_COST.to_le_bytes());
+    out.extend_from_slice(&KDF_T_COST.to_le_bytes());
+    out.extend_from_slice(&KDF_P_COST.to_le_bytes());
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce);
+
+    let key = derive_key(passphrase, &salt, KDF_M_COST, KDF_T_COST, KDF_P_COST)?;
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key.as_slice()))
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: &out,
+            },
+        )
+        .map_err(|_| io::Error::other("Failed to encrypt identity"))?;
+    out.extend_from_slice(&ciphertext);
+
+    Ok(out)
+}
+
+fn decrypt(bytes: &[u8], passphrase: &str) -> io::Result<identity::Keypair> {
+    if bytes.len() < HEADER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Identity file is truncated",
+        ));
+    }
+    let (header, ciphertext) = bytes.split_at(HEADER_LEN);
+
+    let u32_at = |offset: usize| {
+        u32::from_le_bytes(header[offset..offset + 4].try_into().expect("4 byte slice"))
+    };
+    let m_cost = u32_at(MAGIC.len());
+    let t_cost = u32_at(MAGIC.len() + 4);
+    let p_cost = u32_at(MAGIC.len() + 8);
+    let salt = &header[MAGIC.len() + 12..MAGIC.len() + 12 + SALT_LEN];
+    let nonce = Nonce::from_slice(&header[HEADER_LEN - NONCE_LEN..]);
+
+    let key = derive_key(passphrase, salt, m_cost, t_cost, p_cost)?;
+    let plaintext = ChaCha20Poly1305::new(Key::from_slice(key.as_slice()))
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map(Zeroizing::new)
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Wrong passphrase or corrupted identity file",
+            )
+        })?;
+
+    decode(&plaintext)
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> io::Result<Zeroizing<[u8; 32]>> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut_slice())
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    Ok(key)
+}
+
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        create_private_dir(dir)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+
+    builder.create(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path in a fresh directory that is removed again when dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new() -> Self {
+            let dir = env::temp_dir().join(format!("hermes-keystore-{}", OsRng.next_u64()));
+            Self(dir.join("identity.key"))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    fn assert_invalid_data(result: io::Result<identity::Keypair>) {
+        let error = result.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{error}");
+    }
+
+    #[test]
+    fn encrypt_decrypt_roundtrip() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let encrypted = encrypt(&keypair, "correct horse").unwrap();
+        assert!(is_encrypted(&encrypted));
+
+        let decrypted = decrypt(&encrypted, "correct horse").unwrap();
+        assert_eq!(decrypted.public(), keypair.public());
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let encrypted = encrypt(&identity::Keypair::generate_ed25519(), "correct horse").unwrap();
+        assert_invalid_data(decrypt(&encrypted, "battery staple"));
+    }
+
+    #[test]
+    fn tampered_header_is_rejected() {
+        let encrypted = encrypt(&identity::Keypair::generate_ed25519(), "correct horse").unwrap();
+
+        // The magic does not feed into the key, only into the associated data.
+        let mut magic = encrypted.clone();
+        magic[MAGIC.len() - 1] = b'2';
+        assert_invalid_data(decrypt(&magic, "correct horse"));
+
+        // Weaker KDF parameters, as an attacker brute forcing the passphrase would like them.
+        let mut weakened = encrypted;
+        weakened[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&8u32.to_le_bytes());
+        weakened[MAGIC.len() + 4..MAGIC.len() + 8].copy_from_slice(&1u32.to_le_bytes());
+        assert_invalid_data(decrypt(&weakened, "correct horse"));
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let encrypted = encrypt(&identity::Keypair::generate_ed25519(), "correct horse").unwrap();
+
+        assert_invalid_data(decrypt(&encrypted[..HEADER_LEN - 1], "correct horse"));
+        assert_invalid_data(decrypt(&encrypted[..encrypted.len() - 1], "correct horse"));
+    }
+
+    #[test]
+    fn store_creates_a_private_file_and_never_overwrites() {
+        let path = TempPath::new();
+        let keypair = identity::Keypair::generate_ed25519();
+
+        store(&path.0, &keypair, "correct horse").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&path.0), 0o600);
+            assert_eq!(mode(path.0.parent().unwrap()), 0o700);
+        }
+
+        let error = store(&path.0, &identity::Keypair::generate_ed25519(), "other").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        let stored = decrypt(&fs::read(&path.0).unwrap(), "correct horse").unwrap();
+        assert_eq!(stored.public(), keypair.public());
+    }
+}
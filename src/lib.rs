@@ -0,0 +1,32 @@
+//! Peer-to-peer chat over libp2p, using relays and DCUtR hole punching to connect peers behind
+//! NATs.
+//!
+//! [`ChatNode`] embeds a chat node into any tokio application. The `hermes` binary exposes each
+//! module's `run` function as a subcommand.
+
+pub mod access;
+pub mod backoff;
+pub mod behaviour;
+pub mod cli;
+pub mod dcutr;
+pub mod dcutr_chat;
+pub mod dht;
+pub mod directory;
+pub mod doctor;
+pub mod envelope;
+pub mod identity;
+pub mod keystore;
+pub mod lookup;
+pub mod mnemonic;
+pub mod node;
+pub mod peerstore;
+pub mod portmap;
+pub mod relay;
+pub mod rendezvous;
+pub mod room;
+pub mod scoring;
+pub mod startup;
+pub mod username;
+pub mod validation;
+
+pub use node::{ChatEvent, ChatEvents, ChatNode};
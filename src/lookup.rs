@@ -0,0 +1,257 @@
+//! Request-response protocol for resolving a username to the peer that holds it.
+//!
+//! Answers carry the holder's signed [`UsernameRecord`], so a lookup only trusts what the
+//! holder itself signed, never the word of the peer that answered.
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{request_response, Multiaddr, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+use crate::username::UsernameRecord;
+
+/// Protocol name of username lookups.
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/hermes/lookup/1.0.0");
+
+/// Upper bound on the size of a single request or response.
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024;
+
+/// Most addresses included in a single answer.
+pub const MAX_ADDRESSES: usize = 16;
+
+/// Outcome of resolving a username across all peers that were asked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupResult {
+    /// Exactly one peer holds the name, according to a record it signed.
+    Found {
+        peer: PeerId,
+        /// Addresses the answering peers know for `peer`. Unsigned, but dialing checks the
+        /// remote's identity anyway.
+        addresses: Vec<Multiaddr>,
+    },
+    /// Nobody knows the name.
+    NotFound,
+    /// Several peers hold validly signed claims for the name.
+    Ambiguous { peers: Vec<PeerId> },
+}
+
+// === Custom Protocol Definition ===
+
+#[derive(Clone, Default)]
+pub struct LookupCodec();
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LookupRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LookupResponse {
+    Found {
+        /// Boxed, as it is much larger than the other variant.
+        record: Box<UsernameRecord>,
+        addresses: Vec<Multiaddr>,
+    },
+    NotFound,
+}
+
+/// The encoding of [`LookupResponse`] on the wire.
+#[derive(Serialize, Deserialize)]
+enum WireResponse {
+    Found {
+        record: Vec<u8>,
+        addresses: Vec<Vec<u8>>,
+    },
+    NotFound,
+}
+
+impl From<LookupResponse> for WireResponse {
+    fn from(response: LookupResponse) -> Self {
+        match response {
+            LookupResponse::Found { record, addresses } => WireResponse::Found {
+                record: record.encode(),
+                addresses: addresses
+                    .into_iter()
+                    .take(MAX_ADDRESSES)
+                    .map(|address| address.to_vec())
+                    .collect(),
+            },
+            LookupResponse::NotFound => WireResponse::NotFound,
+        }
+    }
+}
+
+impl TryFrom<WireResponse> for LookupResponse {
+    type Error = io::Error;
+
+    fn try_from(response: WireResponse) -> io::Result<Self> {
+        Ok(match response {
+            WireResponse::Found { record, addresses } => LookupResponse::Found {
+                record: Box::new(
+                    UsernameRecord::decode(&record)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                ),
+                // Skip what we cannot parse, the record is what matters.
+                addresses: addresses
+                    .into_iter()
+                    .take(MAX_ADDRESSES)
+                    .filter_map(|address| Multiaddr::try_from(address).ok())
+                    .collect(),
+            },
+            WireResponse::NotFound => LookupResponse::NotFound,
+        })
+    }
+}
+
+async fn read_cbor<T, R>(io: &mut R) -> io::Result<T>
+where
+    T: serde::de::DeserializeOwned,
+    R: AsyncRead + Unpin + Send,
+{
+    let mut buf = Vec::new();
+    io.take(MAX_MESSAGE_SIZE).read_to_end(&mut buf).await?;
+    ciborium::from_reader(buf.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_cbor<T, W>(io: &mut W, value: &T) -> io::Result<()>
+where
+    T: Serialize,
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut buf = Vec::new();
+    ciborium::into_writer(value, &mut buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    io.write_all(&buf).await?;
+    io.close().await
+}
+
+#[async_trait]
+impl request_response::Codec for LookupCodec {
+    type Protocol = StreamProtocol;
+    type Request = LookupRequest;
+    type Response = LookupResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_cbor(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_cbor::<WireResponse, _>(io).await?.try_into()
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: LookupRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_cbor(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: LookupResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_cbor(io, &WireResponse::from(response)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+    use libp2p::identity;
+    use request_response::Codec;
+
+    use super::*;
+
+    fn request(username: &str) -> LookupRequest {
+        LookupRequest {
+            username: username.to_string(),
+        }
+    }
+
+    fn found(addresses: Vec<Multiaddr>) -> LookupResponse {
+        let keypair = identity::Keypair::generate_ed25519();
+        LookupResponse::Found {
+            record: Box::new(UsernameRecord::new(&keypair, "alice").unwrap()),
+            addresses,
+        }
+    }
+
+    fn written<T: Serialize>(value: &T) -> Cursor<Vec<u8>> {
+        let mut io = Cursor::new(Vec::new());
+        block_on(write_cbor(&mut io, value)).unwrap();
+        Cursor::new(io.into_inner())
+    }
+
+    #[test]
+    fn request_roundtrip() {
+        let mut io = Cursor::new(Vec::new());
+        block_on(LookupCodec().write_request(&PROTOCOL, &mut io, request("alice"))).unwrap();
+
+        let mut io = Cursor::new(io.into_inner());
+        let read = block_on(LookupCodec().read_request(&PROTOCOL, &mut io)).unwrap();
+        assert_eq!(read, request("alice"));
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let address: Multiaddr = "/ip4/192.0.2.1/tcp/4001".parse().unwrap();
+        for response in [found(vec![address]), LookupResponse::NotFound] {
+            let mut io = Cursor::new(Vec::new());
+            block_on(LookupCodec().write_response(&PROTOCOL, &mut io, response.clone())).unwrap();
+
+            let mut io = Cursor::new(io.into_inner());
+            let read = block_on(LookupCodec().read_response(&PROTOCOL, &mut io)).unwrap();
+            assert_eq!(read, response);
+        }
+    }
+
+    #[test]
+    fn at_most_max_addresses_are_sent() {
+        let address: Multiaddr = "/ip4/192.0.2.1/tcp/4001".parse().unwrap();
+        let mut io = Cursor::new(Vec::new());
+        let response = found(vec![address; MAX_ADDRESSES + 1]);
+        block_on(LookupCodec().write_response(&PROTOCOL, &mut io, response)).unwrap();
+
+        let mut io = Cursor::new(io.into_inner());
+        match block_on(LookupCodec().read_response(&PROTOCOL, &mut io)).unwrap() {
+            LookupResponse::Found { addresses, .. } => assert_eq!(addresses.len(), MAX_ADDRESSES),
+            LookupResponse::NotFound => panic!("expected a record"),
+        }
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let mut io = written(&request(&"a".repeat(MAX_MESSAGE_SIZE as usize)));
+        let error = block_on(LookupCodec().read_request(&PROTOCOL, &mut io)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let LookupResponse::Found { record, .. } = found(Vec::new()) else {
+            unreachable!()
+        };
+        let mut io = written(&WireResponse::Found {
+            record: record.encode(),
+            addresses: vec![vec![0; MAX_MESSAGE_SIZE as usize]],
+        });
+        let error = block_on(LookupCodec().read_response(&PROTOCOL, &mut io)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // Only as much as the limit was read, the rest is left unbuffered.
+        assert_eq!(io.position(), MAX_MESSAGE_SIZE);
+    }
+}
@@ -1,22 +1,51 @@
-pub mod dcutr;
+use std::error::Error;
 
-use dcutr::{run, Opts};
-// use chat::start_chat;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use hermes::{dcutr, dcutr_chat, directory, doctor, identity, relay, rendezvous};
+use tracing_subscriber::EnvFilter;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let opts = Opts::parse();
-    let (swarm, peer_id) = run(opts).await?;
-    println!("Swarm running with PeerId: {}", peer_id);
-    // println!("Listening on: {:?}", swarm.listeners());
+#[derive(Debug, Parser)]
+#[command(name = "hermes", version, about = "Peer-to-peer chat over libp2p")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    
-    // lets opts
-    // Call chat functionality here
-    // start_chat(swarm, peer_id).await?;
-    
-    Ok(())
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Chat with other peers over gossipsub, connecting through a relay.
+    Chat(dcutr_chat::Opts),
+    /// Establish a direct connection to a peer by hole punching through a relay.
+    Holepunch(dcutr::Opts),
+    /// Run a relay server that clients reserve slots on and hole punch through.
+    Relay(relay::Opts),
+    /// Issue an invite token that lets one peer use a relay started with this identity.
+    RelayInvite(relay::InviteOpts),
+    /// Run a rendezvous server that chat clients discover the members of their rooms through.
+    Rendezvous(rendezvous::Opts),
+    /// Run a directory node that remembers announced usernames and answers lookups for them.
+    Directory(directory::Opts),
+    /// Check whether this node is reachable and hole punching is likely to work.
+    Doctor(doctor::Opts),
+    /// Inspect, move and back up the local identity.
+    #[command(subcommand)]
+    Identity(identity::Command),
 }
 
-// swarm event , peer id 
\ No newline at end of file
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+
+    match Cli::parse().command {
+        Command::Chat(opts) => dcutr_chat::run(opts).await,
+        Command::Holepunch(opts) => dcutr::run(opts).await,
+        Command::Relay(opts) => relay::run(opts).await,
+        Command::RelayInvite(opts) => relay::invite(opts),
+        Command::Rendezvous(opts) => rendezvous::run(opts).await,
+        Command::Directory(opts) => directory::run(opts).await,
+        Command::Doctor(opts) => doctor::run(opts).await,
+        Command::Identity(command) => identity::run(command),
+    }
+}
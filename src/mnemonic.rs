@@ -0,0 +1,112 @@
+//! Paper backups of the node identity as a BIP-39 recovery phrase.
+//!
+//! The 32 byte Ed25519 secret key is used as BIP-39 entropy, which yields 24 English words with a
+//! checksum. Rebuilding the keypair from the phrase restores the same [`PeerId`](libp2p::PeerId).
+
+use std::io;
+
+use bip39::Mnemonic;
+use libp2p::identity;
+use zeroize::Zeroizing;
+
+/// Encodes the secret key of `keypair` as a 24 word recovery phrase.
+///
+/// Only Ed25519 identities can be backed up this way.
+pub fn to_phrase(keypair: &identity::Keypair) -> io::Result<Zeroizing<String>> {
+    let keypair = keypair
+        .clone()
+        .try_into_ed25519()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let secret = keypair.secret();
+
+    let mnemonic = Mnemonic::from_entropy(secret.as_ref())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    Ok(Zeroizing::new(mnemonic.to_string()))
+}
+
+/// Rebuilds the Ed25519 keypair from a recovery phrase produced by [`to_phrase`].
+///
+/// Whitespace between words is not significant and the checksum word is verified.
+pub fn from_phrase(phrase: &str) -> io::Result<identity::Keypair> {
+    let phrase = Zeroizing::new(phrase.split_whitespace().collect::<Vec<_>>().join(" "));
+    let mnemonic = Mnemonic::parse(phrase.as_str())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut entropy = Zeroizing::new(mnemonic.to_entropy());
+    if entropy.len() != 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Expected a 24 word recovery phrase, got {} words",
+                mnemonic.word_count()
+            ),
+        ));
+    }
+
+    identity::Keypair::ed25519_from_bytes(entropy.as_mut_slice())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::insecure_test_identity;
+
+    fn assert_rejected(phrase: &str) {
+        let error = from_phrase(phrase).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{error}");
+    }
+
+    #[test]
+    fn phrase_restores_the_peer_id() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let phrase = to_phrase(&keypair).unwrap();
+        assert_eq!(phrase.split_whitespace().count(), 24);
+
+        let restored = from_phrase(&phrase).unwrap();
+        assert_eq!(
+            restored.public().to_peer_id(),
+            keypair.public().to_peer_id()
+        );
+    }
+
+    #[test]
+    fn whitespace_between_words_is_ignored() {
+        let keypair = insecure_test_identity(1);
+        let phrase = to_phrase(&keypair).unwrap();
+        let spaced = format!("  {}\n", phrase.replace(' ', " \t\n "));
+
+        let restored = from_phrase(&spaced).unwrap();
+        assert_eq!(restored.public(), keypair.public());
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        let phrase = to_phrase(&insecure_test_identity(1)).unwrap();
+        let mut words: Vec<_> = phrase.split_whitespace().collect();
+        words.swap(0, 1);
+        assert_rejected(&words.join(" "));
+
+        let (last, _) = phrase.rsplit_once(' ').unwrap();
+        assert_rejected(&format!("{last} zoo"));
+    }
+
+    #[test]
+    fn unknown_words_are_rejected() {
+        let phrase = to_phrase(&insecure_test_identity(1)).unwrap();
+        let (_, rest) = phrase.split_once(' ').unwrap();
+        assert_rejected(&format!("hermes {rest}"));
+    }
+
+    #[test]
+    fn only_24_word_phrases_are_accepted() {
+        // A valid 12 word phrase of only 16 bytes of entropy.
+        let error = from_phrase(&format!("{}about", "abandon ".repeat(11))).unwrap_err();
+        assert!(error.to_string().contains("got 12 words"), "{error}");
+
+        let phrase = to_phrase(&insecure_test_identity(1)).unwrap();
+        let (shorter, _) = phrase.rsplit_once(' ').unwrap();
+        assert_rejected(shorter);
+    }
+}
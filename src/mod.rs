@@ -1,2 +0,0 @@
-pub mod chat;
-pub mod dcutr;
\ No newline at end of file
@@ -0,0 +1,2541 @@
+//! An embeddable chat node.
+//!
+//! [`ChatNode::start`] builds the swarm, connects to the relay and spawns the event loop onto the
+//! tokio runtime. The returned [`ChatNode`] is a cheap, cloneable handle for issuing commands and
+//! [`ChatEvents`] is the stream of everything that happens on the network.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    stream::{Stream, StreamExt},
+    SinkExt,
+};
+use libp2p::{
+    core::{multiaddr::Protocol, transport::ListenerId, ConnectedPoint},
+    dcutr, gossipsub, identify, identity, kad, mdns, relay, rendezvous, request_response,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, StreamUpgradeError, SwarmEvent,
+    },
+    upnp, Multiaddr, PeerId, Swarm,
+};
+
+use crate::{
+    access::{InviteResponse, InviteToken},
+    backoff::Backoff,
+    behaviour::{self, Behaviour, BehaviourEvent, SwarmOptions, Transports},
+    cli::Mode,
+    dht,
+    envelope::{Envelope, Kind},
+    lookup::{LookupRequest, LookupResponse, LookupResult, MAX_ADDRESSES},
+    peerstore::Peerstore,
+    portmap::{self, PortMapper},
+    relay::{circuit_relay, is_relayed, with_peer_id},
+    rendezvous::room_namespace,
+    room::{self, RoomError},
+    scoring::{self, SpamProtection},
+    startup::{Startup, StartupError, Timeouts},
+    username::{self, RecordError, Registry, UsernameRecord},
+    validation::{Invalid, Validated, Validator},
+};
+
+/// How often the rendezvous server is asked for new members of our rooms, and how often
+/// registrations and our username record are checked for renewal.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Longest registration TTL we believe a rendezvous server, the longest the protocol allows.
+const MAX_REGISTRATION_TTL: Duration = Duration::from_secs(72 * 60 * 60);
+
+/// First and longest delay before reconnecting to a relay we lost.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Peers from the peerstore seen longer ago than this are not redialed on startup.
+const KNOWN_PEER_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Gossipsub protocols start with this, so peers announcing one are chat peers.
+const GOSSIPSUB_PROTOCOL_PREFIX: &str = "/meshsub/";
+
+/// Longest delay between retries of a failed hole punch.
+const MAX_HOLE_PUNCH_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Share of a circuit's duration or data limit after which we warn that it is about to close.
+const CIRCUIT_WARNING_PERCENT: u32 = 80;
+
+/// Whether we reach a peer through a relay or directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionKind {
+    Relayed,
+    Direct,
+}
+
+/// How often a failed hole punch is retried. A retry opens a fresh relayed connection to the
+/// peer, on which DCUtR tries again.
+#[derive(Debug, Clone)]
+pub struct HolePunchRetry {
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub backoff: Duration,
+}
+
+impl Default for HolePunchRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 2,
+            backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Everything needed to start a [`ChatNode`].
+#[derive(Debug)]
+pub struct Config {
+    pub keypair: identity::Keypair,
+    /// Relays to reserve slots on and dial peers through. In [`Mode::Listen`] the node listens
+    /// through all of them at once. Required unless `lan` is set.
+    pub relay_addresses: Vec<Multiaddr>,
+    pub mode: Mode,
+    /// Peer to dial through the relay once connected. Required in [`Mode::Dial`] without a
+    /// rendezvous point.
+    pub remote_peer_id: Option<PeerId>,
+    /// Rendezvous server to register with and discover room members through, ending in
+    /// `/p2p/<peer-id>`.
+    pub rendezvous_point: Option<Multiaddr>,
+    /// Directory node to stay connected to for username lookups, ending in `/p2p/<peer-id>`.
+    pub directory: Option<Multiaddr>,
+    /// Find peers on the local network via mDNS and dial them directly.
+    pub lan: bool,
+    /// Map our listen ports on the router via UPnP.
+    pub upnp: bool,
+    /// Map our listen ports on this gateway, instead of searching for a UPnP gateway.
+    pub port_mapping: Option<portmap::Gateway>,
+    pub transports: Transports,
+    /// Addresses to listen on besides relay circuits.
+    pub listen_addresses: Vec<Multiaddr>,
+    pub username: String,
+    /// Rooms to join on start.
+    pub rooms: Vec<String>,
+    /// Token redeemed with the relays before using them, if they restrict access.
+    pub invite_token: Option<InviteToken>,
+    /// How long connecting to a relay may take before [`ChatNode::start`] gives up on it.
+    pub timeouts: Timeouts,
+    /// Directory to remember the peers we met in. They are redialed on startup, directly if we
+    /// know how, before falling back to relay circuits.
+    pub peerstore: Option<PathBuf>,
+    pub hole_punch_retry: HolePunchRetry,
+    /// How hard to push back on peers that spam or forward invalid messages.
+    pub spam: SpamProtection,
+}
+
+/// Something that happened on the network, as seen by the local node.
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    /// The node is listening on a new address.
+    Listening { address: Multiaddr },
+    /// We are connected to a relay, initially or after losing it.
+    RelayConnected { relay: PeerId, address: Multiaddr },
+    /// We lost the connection to a relay and try again after `retry_in`.
+    RelayDisconnected { relay: PeerId, retry_in: Duration },
+    /// The relay accepted our reservation, so peers can reach us through it.
+    ReservationAccepted { relay: PeerId },
+    /// The relay refused our reservation, so peers cannot reach us through it.
+    ReservationDenied { relay: PeerId, reason: String },
+    /// The relay refused to open a circuit to the peer we dialed.
+    CircuitDenied { relay: PeerId, reason: String },
+    /// A connection to `peer` was established.
+    PeerConnected {
+        peer: PeerId,
+        endpoint: ConnectedPoint,
+    },
+    /// The last connection to `peer` was closed.
+    PeerDisconnected { peer: PeerId },
+    /// We now reach `peer` differently: directly after a hole punch, or only relayed again
+    /// after losing the direct connection.
+    ConnectionChanged { peer: PeerId, kind: ConnectionKind },
+    /// DCUtR upgraded the relayed connection to `peer` to a direct one.
+    HolePunchSucceeded { peer: PeerId },
+    /// DCUtR could not upgrade the relayed connection to `peer`. Retried after `retry_in`, if
+    /// the retry policy allows another attempt.
+    HolePunchFailed {
+        peer: PeerId,
+        error: String,
+        retry_in: Option<Duration>,
+    },
+    /// The relay limits the circuit to `peer` to `duration` or `data_in_bytes`, whichever is
+    /// reached first. The connection closes then unless a hole punch succeeds.
+    CircuitLimited {
+        peer: PeerId,
+        duration: Option<Duration>,
+        data_in_bytes: Option<u64>,
+    },
+    /// The relayed circuit to `peer` reaches its duration limit after `remaining`.
+    CircuitExpiring { peer: PeerId, remaining: Duration },
+    /// The relayed circuit to `peer` reaches its data limit after about `remaining_bytes` more
+    /// chat traffic.
+    CircuitDataExpiring { peer: PeerId, remaining_bytes: u64 },
+    /// Dialing a peer failed.
+    DialFailed {
+        peer: Option<PeerId>,
+        error: String,
+    },
+    /// The rendezvous server told us about a member of `room`.
+    PeerDiscovered {
+        room: String,
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+    /// The router forwards `address` to us, so peers can dial it directly.
+    PortMapped { address: Multiaddr },
+    /// The router stopped forwarding `address` to us.
+    PortMappingExpired { address: Multiaddr },
+    /// No usable router was found or it refused the mapping, so we depend on the relay.
+    PortMappingFailed { reason: String },
+    /// mDNS found a peer on the local network.
+    LanPeerDiscovered {
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+    /// A peer announced the username it goes by, in a record signed with its key.
+    UsernameAnnounced { username: String, peer: PeerId },
+    /// A peer claimed a username another peer already holds.
+    UsernameConflict {
+        username: String,
+        claimed_by: PeerId,
+        owner: PeerId,
+    },
+    /// Messages authored by `peer` are dropped for `duration`, because it flooded us.
+    PeerBlacklisted { peer: PeerId, duration: Duration },
+    /// A chat message was posted in `room`.
+    Message {
+        room: String,
+        id: gossipsub::MessageId,
+        /// The author, if the message was signed.
+        source: Option<PeerId>,
+        /// The peer that forwarded the message to us.
+        propagation_source: PeerId,
+        envelope: Envelope,
+        /// Whether the author is known to hold the username the envelope names.
+        verified: bool,
+    },
+}
+
+/// The event loop of the chat node stopped, so it no longer takes commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeStopped;
+
+impl fmt::Display for NodeStopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the chat node stopped")
+    }
+}
+
+impl Error for NodeStopped {}
+
+/// Handle to a running chat node.
+#[derive(Clone)]
+pub struct ChatNode {
+    local_peer_id: PeerId,
+    sender: mpsc::Sender<Command>,
+}
+
+/// Stream of [`ChatEvent`]s emitted by a running chat node.
+///
+/// Events are buffered until polled. Dropping the stream discards them.
+pub struct ChatEvents {
+    receiver: mpsc::UnboundedReceiver<ChatEvent>,
+}
+
+impl Stream for ChatEvents {
+    type Item = ChatEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl ChatNode {
+    /// Starts a node, waits until the relay told us our observed address and spawns the event
+    /// loop onto the current tokio runtime. In LAN mode without a relay, only waits for the
+    /// listeners.
+    ///
+    /// Fails with a [`StartupError`] if the relay cannot be reached in time.
+    pub async fn start(config: Config) -> Result<(ChatNode, ChatEvents), Box<dyn Error>> {
+        if config.relay_addresses.is_empty() && !config.lan {
+            return Err("A relay address is required outside of LAN mode.".into());
+        }
+        let local_peer_id = config.keypair.public().to_peer_id();
+        let record = UsernameRecord::new(&config.keypair, &config.username)?;
+        let keypair = config.keypair.clone();
+        let rendezvous_point = config
+            .rendezvous_point
+            .map(|address| with_known_peer(address, "rendezvous"))
+            .transpose()?;
+        let directory = config
+            .directory
+            .map(|address| with_known_peer(address, "directory"))
+            .transpose()?;
+        let peerstore = config
+            .peerstore
+            .as_deref()
+            .map(Peerstore::open)
+            .transpose()?;
+        let mut swarm = behaviour::build_swarm(
+            config.keypair,
+            SwarmOptions {
+                lan: config.lan,
+                upnp: config.upnp && config.port_mapping.is_none(),
+                transports: config.transports,
+                scoring: config.spam.profile,
+            },
+        )?;
+
+        // Joining a room twice would leave it listed twice, while subscribed once.
+        let mut rooms: Vec<String> = Vec::new();
+        for room in config.rooms {
+            if !rooms.contains(&room) {
+                rooms.push(room);
+            }
+        }
+        let usernames = gossipsub::IdentTopic::new(username::TOPIC);
+        let pubsub = &mut swarm.behaviour_mut().gossipsub;
+        for room in &rooms {
+            room::validate(room)?;
+            scoring::subscribe(pubsub, &room::topic(room), config.spam.profile)?;
+        }
+        scoring::subscribe(pubsub, &usernames, config.spam.profile)?;
+
+        for address in config.listen_addresses {
+            swarm.listen_on(address)?;
+        }
+
+        let (command_sender, command_receiver) = mpsc::channel(0);
+        let (event_sender, event_receiver) = mpsc::unbounded();
+        let (port_mapper, port_events) = match config.port_mapping {
+            Some(gateway) => {
+                let (port_mapper, port_events) = PortMapper::new(gateway);
+                (Some(port_mapper), port_events)
+            }
+            None => (None, mpsc::unbounded().1),
+        };
+        let mut event_loop = EventLoop {
+            swarm,
+            command_receiver,
+            event_sender,
+            relays: config
+                .relay_addresses
+                .into_iter()
+                .map(RelayConnection::new)
+                .collect(),
+            invite_token: config.invite_token.clone(),
+            reconnect_delay: futures_timer::Delay::new(REFRESH_INTERVAL),
+            mode: config.mode,
+            lan: config.lan,
+            keypair,
+            record,
+            local_peer_id,
+            usernames: Default::default(),
+            pending_lookups: Default::default(),
+            lookup_requests: Default::default(),
+            peer_addresses: Default::default(),
+            rooms,
+            // Start at the current time, so numbers are not reused after a restart.
+            next_seq: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            validator: Validator::new(config.spam.rate_limit, config.spam.flood_limit),
+            scoring: config.spam.profile,
+            blacklist: Default::default(),
+            rendezvous_point,
+            directory,
+            rendezvous_cookies: Default::default(),
+            next_registration: None,
+            refresh_delay: futures_timer::Delay::new(REFRESH_INTERVAL),
+            dht_queries: Default::default(),
+            dht_bootstrapped: false,
+            peerstore,
+            redials: Default::default(),
+            peers: Default::default(),
+            hole_punch_retry: config.hole_punch_retry,
+            circuit_dials: Default::default(),
+            outbound_circuit_limits: Default::default(),
+            peer_delay: futures_timer::Delay::new(REFRESH_INTERVAL),
+            port_mapper,
+            port_events,
+        };
+        event_loop
+            .connect_relays(config.invite_token, config.timeouts)
+            .await?;
+        event_loop.redial_known_peers();
+        event_loop.connect_remote(config.remote_peer_id)?;
+        event_loop.connect_rendezvous();
+        event_loop.connect_directory();
+        event_loop.announce_username();
+        tokio::spawn(event_loop.run());
+
+        Ok((
+            ChatNode {
+                local_peer_id,
+                sender: command_sender,
+            },
+            ChatEvents {
+                receiver: event_receiver,
+            },
+        ))
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Publishes `text` in `room`.
+    pub async fn send(&mut self, room: &str, text: String) -> Result<(), Box<dyn Error + Send>> {
+        self.publish(room, Kind::Text, text).await
+    }
+
+    /// Publishes an action, like "waves", in `room`.
+    pub async fn send_action(
+        &mut self,
+        room: &str,
+        text: String,
+    ) -> Result<(), Box<dyn Error + Send>> {
+        self.publish(room, Kind::Action, text).await
+    }
+
+    async fn publish(
+        &mut self,
+        room: &str,
+        kind: Kind,
+        text: String,
+    ) -> Result<(), Box<dyn Error + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        let command = Command::Publish {
+            room: room.to_string(),
+            kind,
+            text,
+            sender,
+        };
+        self.request(command, receiver)
+            .await
+            .unwrap_or_else(|e| Err(Box::new(e)))
+    }
+
+    /// Joins `room`: subscribes to its topic and, with a rendezvous server, registers in it and
+    /// dials its members.
+    pub async fn join(&mut self, room: &str) -> Result<(), Box<dyn Error + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        let command = Command::Join {
+            room: room.to_string(),
+            sender,
+        };
+        self.request(command, receiver)
+            .await
+            .unwrap_or_else(|e| Err(Box::new(e)))
+    }
+
+    /// Leaves `room`, so its messages are no longer received.
+    pub async fn leave(&mut self, room: &str) -> Result<(), Box<dyn Error + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        let command = Command::Leave {
+            room: room.to_string(),
+            sender,
+        };
+        self.request(command, receiver)
+            .await
+            .unwrap_or_else(|e| Err(Box::new(e)))
+    }
+
+    /// The rooms we are a member of.
+    pub async fn rooms(&mut self) -> Result<Vec<String>, NodeStopped> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(Command::Rooms { sender }, receiver).await
+    }
+
+    /// Resolves `username` to the peer holding it. Uses the signed record we already know, or
+    /// asks every connected peer over the lookup protocol.
+    pub async fn lookup(&mut self, username: &str) -> Result<LookupResult, NodeStopped> {
+        let (sender, receiver) = oneshot::channel();
+        let command = Command::Lookup {
+            username: username.to_string(),
+            sender,
+        };
+        self.request(command, receiver).await
+    }
+
+    /// The peers we are connected to and how.
+    pub async fn peers(&mut self) -> Result<Vec<(PeerId, ConnectionKind)>, NodeStopped> {
+        let (sender, receiver) = oneshot::channel();
+        self.request(Command::Peers { sender }, receiver).await
+    }
+
+    /// Dials `peer` at `addresses` and through a relay circuit.
+    pub async fn dial(
+        &mut self,
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+    ) -> Result<(), Box<dyn Error + Send>> {
+        let (sender, receiver) = oneshot::channel();
+        let command = Command::Dial {
+            peer,
+            addresses,
+            sender,
+        };
+        self.request(command, receiver)
+            .await
+            .unwrap_or_else(|e| Err(Box::new(e)))
+    }
+
+    /// Hands `command` to the event loop and waits for its answer on `receiver`.
+    async fn request<T>(
+        &mut self,
+        command: Command,
+        receiver: oneshot::Receiver<T>,
+    ) -> Result<T, NodeStopped> {
+        self.sender.send(command).await.map_err(|_| NodeStopped)?;
+        receiver.await.map_err(|_| NodeStopped)
+    }
+}
+
+enum Command {
+    Publish {
+        room: String,
+        kind: Kind,
+        text: String,
+        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+    },
+    Lookup {
+        username: String,
+        sender: oneshot::Sender<LookupResult>,
+    },
+    Dial {
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+    },
+    Peers {
+        sender: oneshot::Sender<Vec<(PeerId, ConnectionKind)>>,
+    },
+    Join {
+        room: String,
+        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+    },
+    Leave {
+        room: String,
+        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+    },
+    Rooms {
+        sender: oneshot::Sender<Vec<String>>,
+    },
+}
+
+/// The connections to a peer, and the state of hole punching to it.
+struct PeerState {
+    connections: HashMap<ConnectionId, ConnectionKind>,
+    hole_punch_failures: u32,
+    backoff: Backoff,
+    /// When to retry a failed hole punch.
+    retry_at: Option<Instant>,
+    /// When to warn that the relayed circuit is about to close, and when it closes.
+    circuit_expiry: Option<(Instant, Instant)>,
+    /// Chat traffic over the relayed circuit, if the relay limits its data.
+    circuit_data: Option<CircuitData>,
+}
+
+impl PeerState {
+    fn new(retry: &HolePunchRetry) -> Self {
+        Self {
+            connections: HashMap::new(),
+            hole_punch_failures: 0,
+            backoff: Backoff::new(retry.backoff, MAX_HOLE_PUNCH_BACKOFF),
+            retry_at: None,
+            circuit_expiry: None,
+            circuit_data: None,
+        }
+    }
+
+    /// Direct if any connection is, relayed if all are, none without connections.
+    fn kind(&self) -> Option<ConnectionKind> {
+        let mut kinds = self.connections.values();
+        if kinds.clone().any(|kind| *kind == ConnectionKind::Direct) {
+            Some(ConnectionKind::Direct)
+        } else {
+            kinds.next().copied()
+        }
+    }
+}
+
+/// The limits a relay put on a circuit.
+#[derive(Debug, Clone, Copy)]
+struct CircuitLimit {
+    duration: Option<Duration>,
+    data_in_bytes: Option<u64>,
+}
+
+/// The chat messages exchanged over a relayed circuit, counted against its data limit. Only an
+/// estimate, as the relay also counts the protocol overhead.
+#[derive(Debug)]
+struct CircuitData {
+    /// Bytes the relay lets through in each direction.
+    limit: u64,
+    received: u64,
+    sent: u64,
+    warned: bool,
+}
+
+impl CircuitData {
+    /// Bytes left in the direction closest to the limit.
+    fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.received.max(self.sent))
+    }
+}
+
+/// A relay we stay connected to, and in [`Mode::Listen`] keep a reservation with.
+struct RelayConnection {
+    address: Multiaddr,
+    /// Known once connected, or from the address if it ends in `/p2p/<peer-id>`.
+    peer_id: Option<PeerId>,
+    /// Our pending dial of the relay, if any.
+    dialing: Option<ConnectionId>,
+    /// Our circuit listener on the relay. The relay client renews its reservation while it is
+    /// open.
+    listener: Option<ListenerId>,
+    /// Our invite token redemption with the relay, until it answers. We only listen through the
+    /// relay once it accepted the token.
+    invite: Option<request_response::RequestId>,
+    backoff: Backoff,
+    /// When to reconnect, or to listen again if only the reservation was lost.
+    retry_at: Option<Instant>,
+}
+
+impl RelayConnection {
+    fn new(address: Multiaddr) -> Self {
+        let peer_id = match address.iter().last() {
+            Some(Protocol::P2p(peer_id)) => Some(peer_id),
+            _ => None,
+        };
+        Self {
+            address,
+            peer_id,
+            dialing: None,
+            listener: None,
+            invite: None,
+            backoff: Backoff::new(RECONNECT_BACKOFF, MAX_RECONNECT_BACKOFF),
+            retry_at: None,
+        }
+    }
+
+    /// The address to listen on or dial through, once we know the relay's peer ID.
+    fn circuit_address(&self) -> Option<Multiaddr> {
+        let peer_id = self.peer_id?;
+        Some(with_peer_id(self.address.clone(), peer_id).with(Protocol::P2pCircuit))
+    }
+}
+
+/// A lookup waiting for the answers of the peers we asked.
+#[derive(Default)]
+struct PendingLookup {
+    senders: Vec<oneshot::Sender<LookupResult>>,
+    /// Requests that were neither answered nor failed yet.
+    outstanding: usize,
+    /// Verified holders of the name, with the addresses we were told for them.
+    holders: HashMap<PeerId, Vec<Multiaddr>>,
+}
+
+/// What a DHT query started by a lookup is looking for.
+#[derive(Clone)]
+enum DhtQuery {
+    /// The username record of a name.
+    Username(String),
+    /// The addresses of the only holder found for a name.
+    Addresses { username: String, peer: PeerId },
+}
+
+struct EventLoop {
+    swarm: Swarm<Behaviour>,
+    command_receiver: mpsc::Receiver<Command>,
+    event_sender: mpsc::UnboundedSender<ChatEvent>,
+    relays: Vec<RelayConnection>,
+    /// Redeemed with every relay we connect to.
+    invite_token: Option<InviteToken>,
+    /// Fires when the next relay is due to be retried.
+    reconnect_delay: futures_timer::Delay,
+    mode: Mode,
+    /// Whether mDNS dials the peers on the local network for us.
+    lan: bool,
+    keypair: identity::Keypair,
+    /// Our own username record, renewed before it expires.
+    record: UsernameRecord,
+    local_peer_id: PeerId,
+    /// Usernames announced on the network so far.
+    usernames: Registry,
+    pending_lookups: HashMap<String, PendingLookup>,
+    lookup_requests: HashMap<request_response::RequestId, String>,
+    /// Addresses peers told us they listen on, via identify or a rendezvous server.
+    peer_addresses: HashMap<PeerId, Vec<Multiaddr>>,
+    /// Rooms we are a member of.
+    rooms: Vec<String>,
+    /// Sequence number of the next message we send.
+    next_seq: u64,
+    validator: Validator,
+    scoring: scoring::Profile,
+    /// Peers blacklisted for flooding, and when to let them in again.
+    blacklist: HashMap<PeerId, Instant>,
+    rendezvous_point: Option<(PeerId, Multiaddr)>,
+    directory: Option<(PeerId, Multiaddr)>,
+    /// Where the last discovery in each namespace left off, so only new members are returned.
+    rendezvous_cookies: HashMap<rendezvous::Namespace, rendezvous::Cookie>,
+    /// When to renew our registrations before they expire.
+    next_registration: Option<Instant>,
+    refresh_delay: futures_timer::Delay,
+    /// DHT queries of lookups that no connected peer could answer.
+    dht_queries: HashMap<kad::QueryId, DhtQuery>,
+    /// Whether we joined the DHT through the first peer that speaks it.
+    dht_bootstrapped: bool,
+    peerstore: Option<Peerstore>,
+    /// Direct dials of known peers, retried through the relays if they fail.
+    redials: HashMap<ConnectionId, PeerId>,
+    peers: HashMap<PeerId, PeerState>,
+    hole_punch_retry: HolePunchRetry,
+    /// Relays our pending dials may open a circuit through, by dial.
+    circuit_dials: HashMap<ConnectionId, Vec<PeerId>>,
+    /// Limits of circuits we opened, by dial and relay, until the connection through them is
+    /// established.
+    outbound_circuit_limits: HashMap<ConnectionId, HashMap<PeerId, CircuitLimit>>,
+    /// Fires when a hole punch is due to be retried or a circuit is about to expire.
+    peer_delay: futures_timer::Delay,
+    /// Maps our listen ports on the gateway given in the config, if any.
+    port_mapper: Option<PortMapper>,
+    port_events: mpsc::UnboundedReceiver<portmap::Event>,
+}
+
+impl EventLoop {
+    /// Waits for the listeners, then connects to the relay servers, if any, to learn our external
+    /// address and enable hole-punching. Redeems `invite_token` with each relay.
+    ///
+    /// Succeeds if at least one relay could be used; the others are retried in the background.
+    async fn connect_relays(
+        &mut self,
+        invite_token: Option<InviteToken>,
+        timeouts: Timeouts,
+    ) -> Result<(), StartupError> {
+        let mut deferred = Vec::new();
+        let mut startup = Startup::new(&mut self.swarm, timeouts, |event| deferred.push(event));
+        startup.wait_for_listeners().await;
+        let mut last_error = None;
+        let mut connected = false;
+        for relay in &mut self.relays {
+            match startup
+                .connect_relay(&relay.address, invite_token.clone())
+                .await
+            {
+                Ok(ready) => {
+                    relay.peer_id = Some(ready.peer_id);
+                    connected = true;
+                }
+                Err(e) => {
+                    tracing::warn!(address=%relay.address, "Failed to use relay: {e}");
+                    relay.retry_at = Some(Instant::now() + relay.backoff.next_delay());
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        // Handle what happened meanwhile, like listen addresses and peers found via mDNS.
+        for event in deferred {
+            self.handle_event(event);
+        }
+        self.schedule_reconnect();
+        match last_error {
+            Some(e) if !connected => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets up the relayed connections depending on the mode.
+    fn connect_remote(&mut self, remote_peer_id: Option<PeerId>) -> Result<(), Box<dyn Error>> {
+        if self.relays.is_empty() {
+            // Without a relay, peers are dialed as mDNS discovers them.
+            return Ok(());
+        }
+        match self.mode {
+            Mode::Dial => match remote_peer_id {
+                Some(remote_peer_id) => self.dial_via_relays(remote_peer_id)?,
+                // Room members are dialed as they are discovered.
+                None if self.rendezvous_point.is_some() || self.lan => {}
+                None => {
+                    return Err(
+                        "Dial mode requires a remote peer ID, a rendezvous point or LAN mode."
+                            .into(),
+                    )
+                }
+            },
+            Mode::Listen => {
+                for index in 0..self.relays.len() {
+                    let connected = self.relays[index]
+                        .peer_id
+                        .is_some_and(|peer| self.swarm.is_connected(&peer));
+                    if connected {
+                        self.listen_via_relay(index);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Listens through the relay at `index`, which makes a reservation with it.
+    fn listen_via_relay(&mut self, index: usize) {
+        let Some(address) = self.relays[index].circuit_address() else {
+            return;
+        };
+        tracing::info!(%address, "Listening via relay circuit");
+        match self.swarm.listen_on(address) {
+            Ok(listener) => self.relays[index].listener = Some(listener),
+            Err(e) => {
+                tracing::warn!("Failed to listen via relay: {e}");
+                self.retry_relay(index);
+            }
+        }
+    }
+
+    /// Circuit addresses through every relay we know, the connected ones first.
+    fn circuit_addresses(&self) -> Vec<Multiaddr> {
+        let (mut connected, disconnected): (Vec<_>, Vec<_>) = self
+            .relays
+            .iter()
+            .filter(|relay| relay.peer_id.is_some())
+            .partition(|relay| {
+                relay
+                    .peer_id
+                    .is_some_and(|peer| self.swarm.is_connected(&peer))
+            });
+        connected.extend(disconnected);
+        connected
+            .into_iter()
+            .filter_map(RelayConnection::circuit_address)
+            .collect()
+    }
+
+    /// The relay with `peer_id`, if it is one of ours.
+    fn relay_index(&self, peer_id: &PeerId) -> Option<usize> {
+        self.relays
+            .iter()
+            .position(|relay| relay.peer_id.as_ref() == Some(peer_id))
+    }
+
+    /// Schedules another attempt to connect to, or listen through, the relay at `index`.
+    fn retry_relay(&mut self, index: usize) -> Duration {
+        let relay = &mut self.relays[index];
+        let delay = relay.backoff.next_delay();
+        relay.retry_at = Some(Instant::now() + delay);
+        self.schedule_reconnect();
+        delay
+    }
+
+    /// Sets the reconnect timer to the relay that is due first.
+    fn schedule_reconnect(&mut self) {
+        let now = Instant::now();
+        let next = self
+            .relays
+            .iter()
+            .filter_map(|relay| relay.retry_at)
+            .min()
+            .map_or(REFRESH_INTERVAL, |at| at.saturating_duration_since(now));
+        self.reconnect_delay.reset(next);
+    }
+
+    /// Dials the relays that are due, or listens through them again if still connected.
+    fn reconnect_relays(&mut self) {
+        let now = Instant::now();
+        for index in 0..self.relays.len() {
+            let relay = &mut self.relays[index];
+            if relay.retry_at.is_none_or(|at| at > now) {
+                continue;
+            }
+            relay.retry_at = None;
+
+            let connected = relay
+                .peer_id
+                .is_some_and(|peer| self.swarm.is_connected(&peer));
+            if connected {
+                self.redeem_invite(index);
+                continue;
+            }
+            let opts = match relay.peer_id {
+                Some(peer) => DialOpts::peer_id(peer)
+                    .condition(PeerCondition::Disconnected)
+                    .addresses(vec![relay.address.clone()])
+                    .build(),
+                None => DialOpts::unknown_peer_id()
+                    .address(relay.address.clone())
+                    .build(),
+            };
+            let connection = opts.connection_id();
+            tracing::info!(address=%relay.address, "Reconnecting to relay");
+            match self.swarm.dial(opts) {
+                Ok(()) => self.relays[index].dialing = Some(connection),
+                Err(e) => {
+                    tracing::debug!("Failed to dial relay: {e}");
+                    self.retry_relay(index);
+                }
+            }
+        }
+        self.schedule_reconnect();
+    }
+
+    /// Picks up a relay connection again: redeems the invite and renews the reservation.
+    fn relay_connected(&mut self, index: usize, peer_id: PeerId) {
+        let relay = &mut self.relays[index];
+        relay.peer_id = Some(peer_id);
+        relay.dialing = None;
+        relay.retry_at = None;
+        relay.backoff.reset();
+        self.emit(ChatEvent::RelayConnected {
+            relay: peer_id,
+            address: self.relays[index].address.clone(),
+        });
+
+        self.redeem_invite(index);
+    }
+
+    /// Redeems our invite token with the relay at `index` and listens through it once the relay
+    /// accepted the token. Without a token, listens right away.
+    fn redeem_invite(&mut self, index: usize) {
+        let relay = &self.relays[index];
+        let Some(peer_id) = relay.peer_id else {
+            return;
+        };
+        match self.invite_token.clone() {
+            Some(_) if relay.invite.is_some() => {}
+            Some(token) => {
+                let request = self
+                    .swarm
+                    .behaviour_mut()
+                    .invite
+                    .send_request(&peer_id, token);
+                self.relays[index].invite = Some(request);
+            }
+            None => self.listen_if_needed(index),
+        }
+    }
+
+    /// Listens through the relay at `index` in [`Mode::Listen`], unless we already do.
+    fn listen_if_needed(&mut self, index: usize) {
+        if self.mode == Mode::Listen && self.relays[index].listener.is_none() {
+            self.listen_via_relay(index);
+        }
+    }
+
+    /// Tracks whether the new connection to `peer` is relayed, and reports if that changes how
+    /// we reach it.
+    fn connection_established(
+        &mut self,
+        peer: PeerId,
+        connection: ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let kind = if endpoint.is_relayed() {
+            ConnectionKind::Relayed
+        } else {
+            ConnectionKind::Direct
+        };
+        let retry = &self.hole_punch_retry;
+        let state = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| PeerState::new(retry));
+        let before = state.kind();
+        state.connections.insert(connection, kind);
+        let after = state.kind();
+
+        self.circuit_dials.remove(&connection);
+        let limits = self.outbound_circuit_limits.remove(&connection);
+        if let ConnectedPoint::Dialer { address, .. } = endpoint
+            && let Some(limit) = circuit_relay(address)
+                .and_then(|relay| limits.and_then(|mut limits| limits.remove(&relay)))
+        {
+            self.circuit_limited(peer, limit);
+        }
+        if let (Some(before), Some(after)) = (before, after)
+            && before != after
+        {
+            self.emit(ChatEvent::ConnectionChanged { peer, kind: after });
+        }
+    }
+
+    /// Forgets the closed connection, and reports if we now only reach `peer` through a relay.
+    fn connection_closed(&mut self, peer: PeerId, connection: ConnectionId) {
+        let Some(state) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        let before = state.kind();
+        state.connections.remove(&connection);
+        match state.kind() {
+            None => {
+                self.peers.remove(&peer);
+            }
+            Some(after) if Some(after) != before => {
+                self.emit(ChatEvent::ConnectionChanged { peer, kind: after });
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Attributes the limits of a circuit we opened through `relay` to the oldest of our dials
+    /// that may use it and has no circuit through it yet.
+    fn outbound_circuit_limited(&mut self, relay: PeerId, limit: CircuitLimit) {
+        let dial = self
+            .circuit_dials
+            .iter()
+            .filter(|(dial, relays)| {
+                relays.contains(&relay)
+                    && !self
+                        .outbound_circuit_limits
+                        .get(dial)
+                        .is_some_and(|limits| limits.contains_key(&relay))
+            })
+            .map(|(dial, _)| *dial)
+            .min();
+        match dial {
+            Some(dial) => {
+                self.outbound_circuit_limits
+                    .entry(dial)
+                    .or_default()
+                    .insert(relay, limit);
+            }
+            None => tracing::debug!(%relay, ?limit, "Circuit limit for a dial we do not track"),
+        }
+    }
+
+    /// Forgets the relays and circuit limits of a dial that failed.
+    fn circuit_dial_failed(&mut self, connection: ConnectionId) {
+        self.circuit_dials.remove(&connection);
+        self.outbound_circuit_limits.remove(&connection);
+    }
+
+    /// Reports the limits of the circuit to `peer` and schedules a warning before it expires.
+    fn circuit_limited(&mut self, peer: PeerId, limit: CircuitLimit) {
+        tracing::info!(%peer, ?limit, "Relayed circuit is limited");
+        if let Some(state) = self.peers.get_mut(&peer) {
+            let now = Instant::now();
+            state.circuit_expiry = limit.duration.map(|duration| {
+                (
+                    now + duration * CIRCUIT_WARNING_PERCENT / 100,
+                    now + duration,
+                )
+            });
+            state.circuit_data = limit.data_in_bytes.map(|limit| CircuitData {
+                limit,
+                received: 0,
+                sent: 0,
+                warned: false,
+            });
+            self.schedule_peer_timer();
+        }
+        self.emit(ChatEvent::CircuitLimited {
+            peer,
+            duration: limit.duration,
+            data_in_bytes: limit.data_in_bytes,
+        });
+    }
+
+    /// Counts chat traffic over the relayed circuit to `peer`, and warns once it nears the data
+    /// limit of the circuit.
+    fn circuit_traffic(&mut self, peer: PeerId, received: usize, sent: usize) {
+        let Some(state) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        let relayed = state.kind() == Some(ConnectionKind::Relayed);
+        let Some(data) = state.circuit_data.as_mut().filter(|_| relayed) else {
+            return;
+        };
+        data.received = data.received.saturating_add(received as u64);
+        data.sent = data.sent.saturating_add(sent as u64);
+        let warn_at = data.limit / 100 * u64::from(CIRCUIT_WARNING_PERCENT);
+        if data.warned || data.received.max(data.sent) < warn_at {
+            return;
+        }
+        data.warned = true;
+        let remaining_bytes = data.remaining();
+        tracing::warn!(%peer, remaining_bytes, "Relayed circuit is about to reach its data limit");
+        self.emit(ChatEvent::CircuitDataExpiring {
+            peer,
+            remaining_bytes,
+        });
+    }
+
+    /// Schedules another hole punch to `peer` if the retry policy allows one.
+    fn schedule_hole_punch_retry(&mut self, peer: PeerId) -> Option<Duration> {
+        let attempts = self.hole_punch_retry.attempts;
+        let state = self.peers.get_mut(&peer)?;
+        state.hole_punch_failures += 1;
+        if state.hole_punch_failures > attempts {
+            return None;
+        }
+        let delay = state.backoff.next_delay();
+        state.retry_at = Some(Instant::now() + delay);
+        self.schedule_peer_timer();
+        Some(delay)
+    }
+
+    /// Sets the peer timer to the next hole punch retry or circuit warning that is due.
+    fn schedule_peer_timer(&mut self) {
+        let now = Instant::now();
+        let next = self
+            .peers
+            .values()
+            .flat_map(|state| {
+                [
+                    state.retry_at,
+                    state.circuit_expiry.map(|(warn_at, _)| warn_at),
+                ]
+            })
+            .flatten()
+            .min()
+            .map_or(REFRESH_INTERVAL, |at| at.saturating_duration_since(now));
+        self.peer_delay.reset(next);
+    }
+
+    /// Retries the hole punches and warns about the circuits that are due.
+    fn handle_peer_timers(&mut self) {
+        let now = Instant::now();
+        let mut retries = Vec::new();
+        let mut expiring = Vec::new();
+        for (peer, state) in &mut self.peers {
+            if state.retry_at.is_some_and(|at| at <= now) {
+                state.retry_at = None;
+                retries.push(*peer);
+            }
+            if let Some((warn_at, closes_at)) = state.circuit_expiry
+                && warn_at <= now
+            {
+                state.circuit_expiry = None;
+                if state.kind() == Some(ConnectionKind::Relayed) {
+                    expiring.push((*peer, closes_at.saturating_duration_since(now)));
+                }
+            }
+        }
+        for peer in retries {
+            self.retry_hole_punch(peer);
+        }
+        for (peer, remaining) in expiring {
+            tracing::warn!(%peer, ?remaining, "Relayed circuit is about to expire");
+            self.emit(ChatEvent::CircuitExpiring { peer, remaining });
+        }
+        self.schedule_peer_timer();
+    }
+
+    /// Opens a fresh relayed connection to `peer`, on which DCUtR tries another hole punch.
+    fn retry_hole_punch(&mut self, peer: PeerId) {
+        let connected_directly = self
+            .peers
+            .get(&peer)
+            .is_some_and(|state| state.kind() == Some(ConnectionKind::Direct));
+        if connected_directly {
+            return;
+        }
+        let mut addresses: Vec<_> = self
+            .peer_addresses
+            .get(&peer)
+            .into_iter()
+            .flatten()
+            .filter(|address| is_relayed(address))
+            .cloned()
+            .collect();
+        addresses.extend(
+            self.circuit_addresses()
+                .into_iter()
+                .map(|address| address.with(Protocol::P2p(peer))),
+        );
+        tracing::info!(%peer, ?addresses, "Retrying hole punch");
+        if let Err(e) = self.dial_peer(peer, addresses, PeerCondition::Always) {
+            tracing::warn!(%peer, "Failed to retry hole punch: {e}");
+        }
+    }
+
+    /// Dials the chat peers from the peerstore that we saw recently, at their direct addresses
+    /// first, so we do not need a rendezvous server or a lookup to find them again.
+    fn redial_known_peers(&mut self) {
+        let Some(peerstore) = &self.peerstore else {
+            return;
+        };
+        for (peer, known) in peerstore.recent(KNOWN_PEER_MAX_AGE) {
+            let is_chat_peer = known
+                .protocols
+                .iter()
+                .any(|protocol| protocol.starts_with(GOSSIPSUB_PROTOCOL_PREFIX));
+            if !is_chat_peer || peer == self.local_peer_id {
+                continue;
+            }
+            if known.direct_addresses.is_empty() {
+                self.redial_via_relays(peer, known.relayed_addresses);
+                continue;
+            }
+            tracing::info!(%peer, addresses=?known.direct_addresses, "Redialing known peer");
+            let opts = DialOpts::peer_id(peer)
+                .condition(PeerCondition::Disconnected)
+                .addresses(known.direct_addresses)
+                .build();
+            let connection = opts.connection_id();
+            match self.swarm.dial(opts) {
+                Ok(()) => {
+                    self.redials.insert(connection, peer);
+                }
+                Err(e) => tracing::debug!(%peer, "Not redialing known peer: {e}"),
+            }
+        }
+    }
+
+    /// Dials `peer` through a relay, at the circuit addresses it had before and through ours.
+    fn redial_via_relays(&mut self, peer: PeerId, mut addresses: Vec<Multiaddr>) {
+        addresses.extend(
+            self.circuit_addresses()
+                .into_iter()
+                .map(|address| address.with(Protocol::P2p(peer))),
+        );
+        if addresses.is_empty() {
+            return;
+        }
+        tracing::info!(%peer, ?addresses, "Redialing known peer via relay");
+        if let Err(e) = self.dial_peer(peer, addresses, PeerCondition::Disconnected) {
+            tracing::debug!(%peer, "Not redialing known peer via relay: {e}");
+        }
+    }
+
+    /// Dials the rendezvous server, if any. Registration and discovery start once connected.
+    fn connect_rendezvous(&mut self) {
+        let Some((peer, address)) = self.rendezvous_point.clone() else {
+            return;
+        };
+        if self.swarm.is_connected(&peer) {
+            return;
+        }
+        tracing::info!(%address, "Connecting to rendezvous server");
+        if let Err(e) = self.swarm.dial(address) {
+            tracing::warn!(%peer, "Failed to dial rendezvous server: {e}");
+        }
+    }
+
+    /// Dials the directory node, if any and not connected yet. Once connected, it receives our
+    /// username record and is asked on every lookup like any other peer.
+    fn connect_directory(&mut self) {
+        let Some((peer, address)) = self.directory.clone() else {
+            return;
+        };
+        if self.swarm.is_connected(&peer) {
+            return;
+        }
+        tracing::info!(%address, "Connecting to directory node");
+        if let Err(e) = self.swarm.dial(address) {
+            tracing::warn!(%peer, "Failed to dial directory node: {e}");
+        }
+    }
+
+    /// Registers our external addresses under the namespace of every room we are in.
+    fn register_rooms(&mut self) {
+        for room in self.rooms.clone() {
+            self.register_room(&room);
+        }
+    }
+
+    /// Registers our external addresses under the namespace of `room`.
+    fn register_room(&mut self, room: &str) {
+        let Some((rendezvous_node, _)) = self.rendezvous_point else {
+            return;
+        };
+        let Ok(namespace) = room_namespace(room) else {
+            tracing::warn!(%room, "Room name too long to register");
+            return;
+        };
+        if let Err(e) =
+            self.swarm
+                .behaviour_mut()
+                .rendezvous
+                .register(namespace, rendezvous_node, None)
+        {
+            // Expected in dial mode: without a relay reservation nobody can reach us.
+            tracing::debug!(%room, "Not registering: {e}");
+        }
+    }
+
+    /// Asks the rendezvous server for members of our rooms we have not heard of yet.
+    fn discover_rooms(&mut self) {
+        for room in self.rooms.clone() {
+            self.discover_room(&room);
+        }
+    }
+
+    /// Asks the rendezvous server for members of `room` we have not heard of yet.
+    fn discover_room(&mut self, room: &str) {
+        let Some((rendezvous_node, _)) = self.rendezvous_point else {
+            return;
+        };
+        let Ok(namespace) = room_namespace(room) else {
+            return;
+        };
+        let cookie = self.rendezvous_cookies.get(&namespace).cloned();
+        self.swarm.behaviour_mut().rendezvous.discover(
+            Some(namespace),
+            cookie,
+            None,
+            rendezvous_node,
+        );
+    }
+
+    fn join_room(&mut self, room: String) -> Result<(), RoomError> {
+        room::validate(&room)?;
+        if self.rooms.contains(&room) {
+            return Err(RoomError::AlreadyJoined(room));
+        }
+        scoring::subscribe(
+            &mut self.swarm.behaviour_mut().gossipsub,
+            &room::topic(&room),
+            self.scoring,
+        )
+        .map_err(|e| RoomError::Subscription(e.to_string()))?;
+        tracing::info!(%room, "Joined room");
+
+        let rendezvous_connected = self
+            .rendezvous_point
+            .as_ref()
+            .is_some_and(|(peer, _)| self.swarm.is_connected(peer));
+        if rendezvous_connected {
+            self.register_room(&room);
+            self.discover_room(&room);
+        }
+        self.rooms.push(room);
+        Ok(())
+    }
+
+    fn leave_room(&mut self, room: String) -> Result<(), RoomError> {
+        let Some(index) = self.rooms.iter().position(|joined| *joined == room) else {
+            return Err(RoomError::NotJoined(room));
+        };
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .unsubscribe(&room::topic(&room))
+            .map_err(|e| RoomError::Subscription(e.to_string()))?;
+        self.rooms.remove(index);
+        tracing::info!(%room, "Left room");
+
+        let Ok(namespace) = room_namespace(&room) else {
+            return Ok(());
+        };
+        self.rendezvous_cookies.remove(&namespace);
+        if let Some((rendezvous_node, _)) = self.rendezvous_point
+            && self.swarm.is_connected(&rendezvous_node)
+        {
+            self.swarm
+                .behaviour_mut()
+                .rendezvous
+                .unregister(namespace, rendezvous_node);
+        }
+        Ok(())
+    }
+
+    /// Runs every [`REFRESH_INTERVAL`] to renew registrations and find new room members.
+    fn refresh_discovery(&mut self) {
+        let Some((rendezvous_node, _)) = self.rendezvous_point else {
+            return;
+        };
+        if !self.swarm.is_connected(&rendezvous_node) {
+            // Registration and discovery happen once the connection is established.
+            self.connect_rendezvous();
+            return;
+        }
+        if self
+            .next_registration
+            .is_some_and(|next| Instant::now() >= next)
+        {
+            self.register_rooms();
+        }
+        self.discover_rooms();
+    }
+
+    fn handle_rendezvous_event(&mut self, event: rendezvous::client::Event) {
+        match event {
+            rendezvous::client::Event::Registered { namespace, ttl, .. } => {
+                tracing::info!(%namespace, ttl, "Registered with rendezvous server");
+                // The TTL comes from the server, so do not trust it to be sane.
+                let ttl = Duration::from_secs(ttl).min(MAX_REGISTRATION_TTL);
+                self.next_registration = Some(Instant::now() + ttl / 2);
+            }
+            rendezvous::client::Event::RegisterFailed {
+                namespace, error, ..
+            } => {
+                tracing::warn!(%namespace, ?error, "Failed to register with rendezvous server");
+            }
+            rendezvous::client::Event::Discovered {
+                registrations,
+                cookie,
+                ..
+            } => {
+                if let Some(namespace) = cookie.namespace() {
+                    self.rendezvous_cookies
+                        .insert(namespace.clone(), cookie.clone());
+                }
+                for registration in registrations {
+                    self.handle_registration(registration);
+                }
+            }
+            rendezvous::client::Event::DiscoverFailed {
+                namespace, error, ..
+            } => {
+                tracing::warn!(?namespace, ?error, "Failed to discover room members");
+            }
+            rendezvous::client::Event::Expired { peer } => {
+                tracing::debug!(%peer, "Rendezvous registration expired");
+            }
+        }
+    }
+
+    /// Dials a room member the rendezvous server told us about.
+    fn handle_registration(&mut self, registration: rendezvous::Registration) {
+        let peer = registration.record.peer_id();
+        if peer == self.local_peer_id {
+            return;
+        }
+        let Some(room) = self
+            .rooms
+            .iter()
+            .find(|room| room_namespace(room).is_ok_and(|ns| ns == registration.namespace))
+            .cloned()
+        else {
+            return;
+        };
+        let addresses = registration.record.addresses().to_vec();
+        self.peer_addresses.insert(peer, addresses.clone());
+        self.emit(ChatEvent::PeerDiscovered {
+            room,
+            peer,
+            addresses: addresses.clone(),
+        });
+
+        if self.swarm.is_connected(&peer) {
+            return;
+        }
+        if let Err(e) = self.dial_peer(peer, addresses, PeerCondition::Disconnected) {
+            tracing::debug!(%peer, "Failed to dial discovered peer: {e}");
+        }
+    }
+
+    /// Tells the DHT and the rendezvous server about a change of our external addresses.
+    fn advertise_addresses(&mut self) {
+        self.publish_addresses();
+        if self
+            .rendezvous_point
+            .as_ref()
+            .is_some_and(|(peer, _)| self.swarm.is_connected(peer))
+        {
+            self.register_rooms();
+        }
+    }
+
+    /// Dials the peers mDNS found on the local network.
+    fn handle_lan_peers(&mut self, peers: Vec<(PeerId, Multiaddr)>) {
+        let mut discovered: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+        for (peer, address) in peers {
+            discovered.entry(peer).or_default().push(address);
+        }
+
+        for (peer, addresses) in discovered {
+            let known = self.peer_addresses.entry(peer).or_default();
+            known.extend(addresses.iter().cloned());
+            known.sort();
+            known.dedup();
+            known.truncate(MAX_ADDRESSES);
+            self.emit(ChatEvent::LanPeerDiscovered {
+                peer,
+                addresses: addresses.clone(),
+            });
+
+            if self.swarm.is_connected(&peer) {
+                continue;
+            }
+            let opts = DialOpts::peer_id(peer)
+                .condition(PeerCondition::Disconnected)
+                .addresses(addresses)
+                .build();
+            if let Err(e) = self.swarm.dial(opts) {
+                tracing::debug!(%peer, "Failed to dial peer on the local network: {e}");
+            }
+        }
+    }
+
+    /// Informs the other peers of our username and PeerId.
+    fn announce_username(&mut self) {
+        if let Err(e) = self
+            .usernames
+            .insert(self.record.clone(), SystemTime::now())
+        {
+            tracing::warn!(username=%self.record.username(), "Cannot claim username: {e}");
+        }
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(
+            gossipsub::IdentTopic::new(username::TOPIC),
+            self.record.encode(),
+        ) {
+            tracing::debug!("Failed to announce username: {e:?}");
+        }
+        self.put_dht_record(dht::username_record(&self.record));
+        self.publish_addresses();
+    }
+
+    /// Publishes our signed addresses in the DHT, so lookups there can reach us.
+    fn publish_addresses(&mut self) {
+        let addresses: Vec<Multiaddr> = self.swarm.external_addresses().cloned().collect();
+        if addresses.is_empty() {
+            return;
+        }
+        match dht::peer_record(&self.keypair, addresses) {
+            Ok(record) => self.put_dht_record(record),
+            Err(e) => tracing::warn!("Failed to sign peer record: {e}"),
+        }
+    }
+
+    /// Stores `record` locally and with the peers closest to its key.
+    ///
+    /// Kademlia replicates stored records on its own, so records put before we know any peer
+    /// still reach the DHT once we join it.
+    fn put_dht_record(&mut self, record: kad::Record) {
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .kad
+            .put_record(record, kad::Quorum::One)
+        {
+            tracing::debug!("Failed to store DHT record: {e:?}");
+        }
+    }
+
+    /// Signs a fresh username record and announces it.
+    ///
+    /// Every announcement needs a new record, as gossipsub drops messages it has seen before.
+    fn renew_username(&mut self) {
+        match UsernameRecord::new(&self.keypair, self.record.username()) {
+            Ok(record) => self.record = record,
+            Err(e) => {
+                tracing::warn!("Failed to renew username record: {e}");
+                return;
+            }
+        }
+        self.announce_username();
+    }
+
+    /// Renews our username record once half of its lifetime has passed.
+    fn refresh_username(&mut self) {
+        if self
+            .record
+            .is_expired(SystemTime::now() + username::RECORD_TTL / 2)
+        {
+            self.renew_username();
+        }
+    }
+
+    /// Dials `peer` through our relays, falling over to the next one if a circuit fails.
+    fn dial_via_relays(&mut self, peer: PeerId) -> Result<(), libp2p::swarm::DialError> {
+        let addresses = self.circuit_addresses();
+        tracing::info!(%peer, ?addresses, "Dialing via relay circuit");
+        self.dial_peer(peer, addresses, PeerCondition::Disconnected)
+    }
+
+    /// Dials `peer` at `addresses`. Remembers the relays of the circuit addresses among them, so
+    /// the limits of a circuit the dial opens go with its connection.
+    fn dial_peer(
+        &mut self,
+        peer: PeerId,
+        addresses: Vec<Multiaddr>,
+        condition: PeerCondition,
+    ) -> Result<(), libp2p::swarm::DialError> {
+        let relays: Vec<_> = addresses.iter().filter_map(circuit_relay).collect();
+        let opts = DialOpts::peer_id(peer)
+            .condition(condition)
+            .addresses(addresses)
+            .build();
+        let connection = opts.connection_id();
+        self.swarm.dial(opts)?;
+        if !relays.is_empty() {
+            self.circuit_dials.insert(connection, relays);
+        }
+        Ok(())
+    }
+
+    async fn run(mut self) {
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.handle_event(event),
+                _ = &mut self.reconnect_delay => self.reconnect_relays(),
+                _ = &mut self.peer_delay => self.handle_peer_timers(),
+                Some(event) = self.port_events.next() => self.handle_port_event(event),
+                _ = &mut self.refresh_delay => {
+                    self.refresh_delay.reset(REFRESH_INTERVAL);
+                    self.refresh_discovery();
+                    self.connect_directory();
+                    self.refresh_username();
+                    self.expire_offences();
+                }
+                command = self.command_receiver.next() => match command {
+                    Some(command) => self.handle_command(command),
+                    // Every handle was dropped, shut down.
+                    None => return,
+                },
+            }
+        }
+    }
+
+    /// Advertises the addresses our own port mapper got forwarded, and stops advertising those
+    /// it lost.
+    fn handle_port_event(&mut self, event: portmap::Event) {
+        match event {
+            portmap::Event::Mapped { local, external } => {
+                tracing::info!(%local, %external, "Mapped port on the gateway");
+                self.swarm.add_external_address(external.clone());
+                self.advertise_addresses();
+                self.emit(ChatEvent::PortMapped { address: external });
+            }
+            portmap::Event::Expired { external } => {
+                tracing::warn!(%external, "Port mapping expired");
+                self.port_mapping_expired(external);
+            }
+            portmap::Event::Failed { local, reason } => {
+                tracing::warn!(%local, "Port mapping failed: {reason}");
+                self.emit(ChatEvent::PortMappingFailed {
+                    reason: format!("{local}: {reason}"),
+                });
+            }
+        }
+    }
+
+    /// Stops advertising `address`, which the router no longer forwards to us.
+    fn port_mapping_expired(&mut self, address: Multiaddr) {
+        self.swarm.remove_external_address(&address);
+        self.advertise_addresses();
+        self.emit(ChatEvent::PortMappingExpired { address });
+    }
+
+    fn emit(&self, event: ChatEvent) {
+        // The embedder may have dropped the event stream; the node keeps running regardless.
+        let _ = self.event_sender.unbounded_send(event);
+    }
+
+    fn handle_event<E>(&mut self, event: SwarmEvent<BehaviourEvent, E>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                // Relayed addresses are how other members reach us, so advertise them.
+                if address.iter().any(|p| p == Protocol::P2pCircuit) {
+                    self.swarm.add_external_address(address.clone());
+                    self.advertise_addresses();
+                }
+                if let Some(port_mapper) = &mut self.port_mapper {
+                    port_mapper.add(&address);
+                }
+                self.emit(ChatEvent::Listening { address });
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                if let Some(port_mapper) = &mut self.port_mapper {
+                    port_mapper.remove(&address);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp::Event::NewExternalAddr(address))) => {
+                // The behaviour already confirmed the address as external.
+                self.advertise_addresses();
+                self.emit(ChatEvent::PortMapped { address });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp::Event::ExpiredExternalAddr(
+                address,
+            ))) => {
+                tracing::warn!(%address, "UPnP port mapping expired");
+                self.port_mapping_expired(address);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp::Event::GatewayNotFound)) => {
+                self.emit(ChatEvent::PortMappingFailed {
+                    reason: "no UPnP gateway found".to_string(),
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Upnp(upnp::Event::NonRoutableGateway)) => {
+                self.emit(ChatEvent::PortMappingFailed {
+                    reason: "the gateway has no public address".to_string(),
+                });
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                self.redials.remove(&connection_id);
+                self.connection_established(peer_id, connection_id, &endpoint);
+                if let Some(peerstore) = &self.peerstore {
+                    let address = match &endpoint {
+                        ConnectedPoint::Dialer { address, .. } => Some(address),
+                        ConnectedPoint::Listener { .. } => None,
+                    };
+                    peerstore.connected(peer_id, address);
+                }
+                if num_established.get() == 1
+                    && let Some(index) = self.relays.iter().position(|relay| {
+                        relay.dialing == Some(connection_id) || relay.peer_id == Some(peer_id)
+                    })
+                {
+                    self.relay_connected(index, peer_id);
+                }
+                if num_established.get() == 1
+                    && self
+                        .rendezvous_point
+                        .as_ref()
+                        .is_some_and(|(peer, _)| *peer == peer_id)
+                {
+                    self.register_rooms();
+                    self.discover_rooms();
+                }
+                // Explicitly add the new peer to gossipsub's mesh.
+                self.swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .add_explicit_peer(&peer_id);
+                self.emit(ChatEvent::PeerConnected {
+                    peer: peer_id,
+                    endpoint,
+                });
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                num_established,
+                ..
+            } => {
+                self.connection_closed(peer_id, connection_id);
+                if num_established > 0 {
+                    return;
+                }
+                // Remove peer from gossipsub mesh.
+                self.swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .remove_explicit_peer(&peer_id);
+                self.emit(ChatEvent::PeerDisconnected { peer: peer_id });
+                if let Some(index) = self.relay_index(&peer_id) {
+                    self.relays[index].invite = None;
+                    let retry_in = self.retry_relay(index);
+                    tracing::warn!(relay=%peer_id, ?retry_in, "Lost connection to relay");
+                    self.emit(ChatEvent::RelayDisconnected {
+                        relay: peer_id,
+                        retry_in,
+                    });
+                }
+            }
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                reason,
+                ..
+            } => {
+                if let Some(index) = self
+                    .relays
+                    .iter()
+                    .position(|relay| relay.listener == Some(listener_id))
+                {
+                    tracing::info!(?reason, "Relay circuit listener closed");
+                    self.relays[index].listener = None;
+                    // Reconnecting listens again; only retry here if the connection is still up.
+                    let connected = self.relays[index]
+                        .peer_id
+                        .is_some_and(|peer| self.swarm.is_connected(&peer));
+                    if connected && self.relays[index].retry_at.is_none() {
+                        self.retry_relay(index);
+                    }
+                }
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
+                if let Some(index) = self
+                    .relays
+                    .iter()
+                    .position(|relay| relay.dialing == Some(connection_id))
+                {
+                    self.relays[index].dialing = None;
+                    let retry_in = self.retry_relay(index);
+                    tracing::warn!(?retry_in, "Failed to reconnect to relay: {error}");
+                }
+                self.circuit_dial_failed(connection_id);
+                if let Some(peer) = self.redials.remove(&connection_id) {
+                    let relayed = self
+                        .peerstore
+                        .as_ref()
+                        .and_then(|peerstore| peerstore.get(&peer))
+                        .map(|known| known.relayed_addresses)
+                        .unwrap_or_default();
+                    self.redial_via_relays(peer, relayed);
+                }
+                self.emit(ChatEvent::DialFailed {
+                    peer: peer_id,
+                    error: error.to_string(),
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::OutboundCircuitEstablished {
+                    relay_peer_id,
+                    limit: Some(limit),
+                },
+            )) => {
+                // The connection through the circuit follows, that is when we learn the peer.
+                self.outbound_circuit_limited(
+                    relay_peer_id,
+                    CircuitLimit {
+                        duration: limit.duration(),
+                        data_in_bytes: limit.data_in_bytes(),
+                    },
+                );
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::InboundCircuitEstablished {
+                    src_peer_id,
+                    limit: Some(limit),
+                },
+            )) => {
+                self.circuit_limited(
+                    src_peer_id,
+                    CircuitLimit {
+                        duration: limit.duration(),
+                        data_in_bytes: limit.data_in_bytes(),
+                    },
+                );
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted { relay_peer_id, .. },
+            )) => {
+                self.emit(ChatEvent::ReservationAccepted {
+                    relay: relay_peer_id,
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqFailed {
+                    relay_peer_id,
+                    error,
+                    ..
+                },
+            )) => {
+                self.emit(ChatEvent::ReservationDenied {
+                    relay: relay_peer_id,
+                    reason: denial_reason(&error),
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::OutboundCircuitReqFailed {
+                    relay_peer_id,
+                    error,
+                },
+            )) => {
+                self.emit(ChatEvent::CircuitDenied {
+                    relay: relay_peer_id,
+                    reason: denial_reason(&error),
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(event)) => {
+                self.handle_rendezvous_event(event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Lookup(event)) => {
+                self.handle_lookup_event(event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Invite(event)) => {
+                self.handle_invite_event(event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => {
+                if info.protocols.contains(&dht::PROTOCOL) {
+                    self.add_dht_peer(peer_id, &info.listen_addrs);
+                }
+                if let Some(peerstore) = &self.peerstore {
+                    peerstore.identified(peer_id, &info);
+                }
+                let mut addresses = info.listen_addrs;
+                addresses.truncate(MAX_ADDRESSES);
+                self.peer_addresses.insert(peer_id, addresses);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kad(event)) => {
+                self.handle_dht_event(event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
+                dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id },
+            )) => {
+                tracing::info!(peer=%remote_peer_id, "Hole punch succeeded");
+                if let Some(peerstore) = &self.peerstore {
+                    peerstore.hole_punched(remote_peer_id, true);
+                }
+                if let Some(state) = self.peers.get_mut(&remote_peer_id) {
+                    state.hole_punch_failures = 0;
+                    state.backoff.reset();
+                    state.retry_at = None;
+                    state.circuit_expiry = None;
+                    state.circuit_data = None;
+                }
+                self.emit(ChatEvent::HolePunchSucceeded {
+                    peer: remote_peer_id,
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(
+                dcutr::Event::DirectConnectionUpgradeFailed {
+                    remote_peer_id,
+                    error,
+                },
+            )) => {
+                tracing::info!(peer=%remote_peer_id, "Hole punch failed: {error}");
+                if let Some(peerstore) = &self.peerstore {
+                    peerstore.hole_punched(remote_peer_id, false);
+                }
+                let retry_in = self.schedule_hole_punch_retry(remote_peer_id);
+                self.emit(ChatEvent::HolePunchFailed {
+                    peer: remote_peer_id,
+                    error: error.to_string(),
+                    retry_in,
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
+                self.handle_lan_peers(peers);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => {
+                self.circuit_traffic(propagation_source, message.data.len(), 0);
+                if message.topic.as_str() == username::TOPIC {
+                    self.handle_username_record(message, message_id, propagation_source);
+                    return;
+                }
+                let Some(room) = room::room_of(&message.topic)
+                    .filter(|room| self.rooms.iter().any(|joined| joined == room))
+                    .map(str::to_string)
+                else {
+                    // Not a room of ours, possibly one we just left.
+                    self.report_validation(
+                        &message_id,
+                        &propagation_source,
+                        gossipsub::MessageAcceptance::Ignore,
+                    );
+                    return;
+                };
+                let validated = match self.validator.validate(&message, &room, &self.usernames) {
+                    Ok(validated) => validated,
+                    Err(invalid) => {
+                        self.drop_message(&message, &message_id, propagation_source, invalid);
+                        return;
+                    }
+                };
+                self.report_validation(
+                    &message_id,
+                    &propagation_source,
+                    gossipsub::MessageAcceptance::Accept,
+                );
+                self.handle_message(room, message, message_id, propagation_source, validated);
+            }
+            SwarmEvent::Behaviour(event) => {
+                // Log other behaviours for debugging
+                tracing::debug!(?event);
+            }
+            _ => {}
+        }
+    }
+
+    /// Tells gossipsub whether to forward a message, which it holds back until validated.
+    fn report_validation(
+        &mut self,
+        id: &gossipsub::MessageId,
+        propagation_source: &PeerId,
+        acceptance: gossipsub::MessageAcceptance,
+    ) {
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(id, propagation_source, acceptance)
+        {
+            tracing::debug!("Failed to report message validation result: {e:?}");
+        }
+    }
+
+    /// Keeps an invalid message from being forwarded and holds it against whoever is to blame.
+    fn drop_message(
+        &mut self,
+        message: &gossipsub::Message,
+        id: &gossipsub::MessageId,
+        propagation_source: PeerId,
+        invalid: Invalid,
+    ) {
+        tracing::debug!(source=%propagation_source, "Dropped message: {invalid}");
+        // Gossipsub counts rejected messages against the peer that forwarded them itself.
+        self.report_validation(id, &propagation_source, invalid.acceptance());
+
+        if let (Invalid::RateLimited, Some(author)) = (invalid, message.source) {
+            // Floods only count against the peer that forwarded them if it wrote them.
+            if author == propagation_source {
+                self.penalize(author);
+            }
+            if self.validator.flooded(author) {
+                let duration = self.scoring.blacklist_duration();
+                tracing::warn!(peer=%author, ?duration, "Blacklisted peer for flooding");
+                self.swarm.behaviour_mut().gossipsub.blacklist_peer(&author);
+                self.blacklist.insert(author, Instant::now() + duration);
+                self.emit(ChatEvent::PeerBlacklisted {
+                    peer: author,
+                    duration,
+                });
+            }
+        }
+    }
+
+    /// Lowers the score of `peer` for sending us more messages than its rate limit allows.
+    fn penalize(&mut self, peer: PeerId) {
+        let score = self.validator.offence(peer);
+        tracing::debug!(%peer, score, "Peer exceeded its rate limit");
+        self.swarm
+            .behaviour_mut()
+            .gossipsub
+            .set_application_score(&peer, score);
+    }
+
+    /// Restores the score of peers whose offences expired, and lets in again the peers whose
+    /// blacklisting ended.
+    fn expire_offences(&mut self) {
+        for (peer, score) in self.validator.expire() {
+            self.swarm
+                .behaviour_mut()
+                .gossipsub
+                .set_application_score(&peer, score);
+        }
+        let now = Instant::now();
+        let gossipsub = &mut self.swarm.behaviour_mut().gossipsub;
+        self.blacklist.retain(|peer, until| {
+            if *until > now {
+                return true;
+            }
+            tracing::info!(%peer, "Peer is no longer blacklisted");
+            gossipsub.remove_blacklisted_peer(peer);
+            false
+        });
+    }
+
+    /// Verifies a username record and only forwards it if we accept it ourselves.
+    fn handle_username_record(
+        &mut self,
+        message: gossipsub::Message,
+        id: gossipsub::MessageId,
+        propagation_source: PeerId,
+    ) {
+        if let Err(invalid) = self.validator.admit(&message) {
+            self.drop_message(&message, &id, propagation_source, invalid);
+            return;
+        }
+        let record = match UsernameRecord::decode(&message.data) {
+            Ok(record) => record,
+            Err(e) => {
+                tracing::debug!(source=%propagation_source, "Dropped username record: {e}");
+                self.report_validation(
+                    &id,
+                    &propagation_source,
+                    gossipsub::MessageAcceptance::Reject,
+                );
+                return;
+            }
+        };
+
+        let username = record.username().to_string();
+        let peer = record.peer();
+        let result = self.usernames.insert(record, SystemTime::now());
+        let acceptance = username::acceptance(result.as_ref().copied());
+        self.report_validation(&id, &propagation_source, acceptance);
+        match result {
+            Ok(()) => self.emit(ChatEvent::UsernameAnnounced { username, peer }),
+            Err(RecordError::Taken { owner }) => {
+                tracing::warn!(
+                    %username,
+                    claimed_by=%peer,
+                    %owner,
+                    "Rejected claim of a username held by another peer"
+                );
+                self.emit(ChatEvent::UsernameConflict {
+                    username,
+                    claimed_by: peer,
+                    owner,
+                });
+            }
+            Err(RecordError::Stale) => {}
+            Err(e) => {
+                tracing::debug!(source=%propagation_source, "Dropped username record: {e}");
+            }
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        room: String,
+        message: gossipsub::Message,
+        id: gossipsub::MessageId,
+        propagation_source: PeerId,
+        validated: Validated,
+    ) {
+        self.emit(ChatEvent::Message {
+            room,
+            id,
+            source: message.source,
+            propagation_source,
+            envelope: validated.envelope,
+            verified: validated.verified,
+        });
+    }
+
+    /// Addresses we can tell others to reach `peer` at.
+    fn addresses_of(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        let mut addresses = if *peer == self.local_peer_id {
+            self.swarm.external_addresses().cloned().collect()
+        } else {
+            self.peer_addresses.get(peer).cloned().unwrap_or_default()
+        };
+        addresses.truncate(MAX_ADDRESSES);
+        addresses
+    }
+
+    /// Answers a lookup from our own registry, or asks every connected peer.
+    fn start_lookup(&mut self, username: String, sender: oneshot::Sender<LookupResult>) {
+        if let Some(peer) = self
+            .usernames
+            .get(&username, SystemTime::now())
+            .map(UsernameRecord::peer)
+        {
+            let addresses = self.addresses_of(&peer);
+            let _ = sender.send(LookupResult::Found { peer, addresses });
+            return;
+        }
+
+        if let Some(pending) = self.pending_lookups.get_mut(&username) {
+            pending.senders.push(sender);
+            return;
+        }
+
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer in &peers {
+            let request_id = self.swarm.behaviour_mut().lookup.send_request(
+                peer,
+                LookupRequest {
+                    username: username.clone(),
+                },
+            );
+            self.lookup_requests.insert(request_id, username.clone());
+        }
+        self.pending_lookups.insert(
+            username.clone(),
+            PendingLookup {
+                senders: vec![sender],
+                outstanding: peers.len(),
+                holders: Default::default(),
+            },
+        );
+        if peers.is_empty() {
+            self.query_dht(DhtQuery::Username(username));
+        }
+    }
+
+    /// Listens through a relay once it accepted our invite token, or tries again later.
+    fn handle_invite_event(&mut self, event: request_response::Event<InviteToken, InviteResponse>) {
+        let (peer, request_id, result) = match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+            } => (peer, request_id, Ok(response)),
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => (peer, request_id, Err(error)),
+            _ => return,
+        };
+        let Some(index) = self
+            .relays
+            .iter()
+            .position(|relay| relay.invite == Some(request_id))
+        else {
+            return;
+        };
+        self.relays[index].invite = None;
+
+        match result {
+            Ok(InviteResponse::Accepted) => {
+                tracing::info!(relay=%peer, "Relay accepted our invite token");
+                self.listen_if_needed(index);
+            }
+            Ok(InviteResponse::Rejected(reason)) => {
+                let retry_in = self.retry_relay(index);
+                tracing::warn!(relay=%peer, ?retry_in, "Relay rejected our invite token: {reason}");
+                self.emit(ChatEvent::ReservationDenied {
+                    relay: peer,
+                    reason: format!("relay rejected our invite token: {reason}"),
+                });
+            }
+            Err(error) => {
+                let retry_in = self.retry_relay(index);
+                tracing::warn!(relay=%peer, ?retry_in, "Failed to redeem invite token: {error}");
+            }
+        }
+    }
+
+    fn handle_lookup_event(
+        &mut self,
+        event: request_response::Event<LookupRequest, LookupResponse>,
+    ) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            } => {
+                let response = match self.usernames.get(&request.username, SystemTime::now()) {
+                    Some(record) => LookupResponse::Found {
+                        record: Box::new(record.clone()),
+                        addresses: self.addresses_of(&record.peer()),
+                    },
+                    None => LookupResponse::NotFound,
+                };
+                tracing::debug!(%peer, username=%request.username, ?response, "Answering lookup");
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .lookup
+                    .send_response(channel, response);
+            }
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                let Some(username) = self.lookup_requests.remove(&request_id) else {
+                    return;
+                };
+                if let LookupResponse::Found { record, addresses } = response {
+                    // The codec checked the signature, we check it is the name we asked for.
+                    if record.username() != username || record.is_expired(SystemTime::now()) {
+                        tracing::warn!(%peer, %username, "Ignoring lookup answer for another name");
+                    } else if let Some(pending) = self.pending_lookups.get_mut(&username) {
+                        pending
+                            .holders
+                            .entry(record.peer())
+                            .or_default()
+                            .extend(addresses);
+                    }
+                }
+                self.lookup_answered(&username);
+            }
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                let Some(username) = self.lookup_requests.remove(&request_id) else {
+                    return;
+                };
+                tracing::debug!(%peer, %username, "Lookup request failed: {error}");
+                self.lookup_answered(&username);
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                tracing::debug!(%peer, "Failed to answer lookup: {error}");
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Counts one more answer to the lookup of `username` and resolves it once all are in.
+    fn lookup_answered(&mut self, username: &str) {
+        let Some(pending) = self.pending_lookups.get_mut(username) else {
+            return;
+        };
+        pending.outstanding -= 1;
+        if pending.outstanding > 0 {
+            return;
+        }
+        if pending.holders.is_empty() {
+            // No peer we asked knows the name, not even a directory node.
+            self.query_dht(DhtQuery::Username(username.to_string()));
+            return;
+        }
+        self.finish_lookup(username);
+    }
+
+    /// Resolves the lookup of `username` with the holders found so far.
+    fn finish_lookup(&mut self, username: &str) {
+        let pending = self
+            .pending_lookups
+            .remove(username)
+            .expect("pending lookup to exist");
+        let result = match pending.holders.len() {
+            0 => LookupResult::NotFound,
+            1 => {
+                let (peer, mut addresses) = pending
+                    .holders
+                    .into_iter()
+                    .next()
+                    .expect("exactly one holder");
+                addresses.sort();
+                addresses.dedup();
+                LookupResult::Found { peer, addresses }
+            }
+            _ => LookupResult::Ambiguous {
+                peers: pending.holders.into_keys().collect(),
+            },
+        };
+        for sender in pending.senders {
+            let _ = sender.send(result.clone());
+        }
+    }
+
+    /// Adds a peer that speaks our DHT protocol and joins the DHT through the first one.
+    fn add_dht_peer(&mut self, peer: PeerId, addresses: &[Multiaddr]) {
+        let kad = &mut self.swarm.behaviour_mut().kad;
+        for address in addresses {
+            kad.add_address(&peer, address.clone());
+        }
+        if self.dht_bootstrapped {
+            return;
+        }
+        match kad.bootstrap() {
+            Ok(_) => self.dht_bootstrapped = true,
+            Err(e) => tracing::debug!("Failed to bootstrap DHT: {e:?}"),
+        }
+    }
+
+    fn query_dht(&mut self, query: DhtQuery) {
+        let key = match &query {
+            DhtQuery::Username(username) => dht::username_key(username),
+            DhtQuery::Addresses { peer, .. } => dht::peer_key(peer),
+        };
+        let id = self.swarm.behaviour_mut().kad.get_record(key);
+        self.dht_queries.insert(id, query);
+    }
+
+    fn handle_dht_event(&mut self, event: kad::Event) {
+        match event {
+            kad::Event::InboundRequest {
+                request:
+                    kad::InboundRequest::PutRecord {
+                        source,
+                        record: Some(record),
+                        ..
+                    },
+            } => {
+                dht::store_inbound(&mut self.swarm.behaviour_mut().kad, source, record);
+            }
+            kad::Event::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetRecord(result),
+                step,
+                ..
+            } => {
+                let Some(query) = self.dht_queries.get(&id).cloned() else {
+                    return;
+                };
+                match result {
+                    Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord { record, .. })) => {
+                        self.handle_dht_record(&query, &record)
+                    }
+                    Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => {}
+                    Err(e) => tracing::debug!("DHT query failed: {e:?}"),
+                }
+                if step.last {
+                    self.dht_queries.remove(&id);
+                    self.dht_query_finished(query);
+                }
+            }
+            kad::Event::OutboundQueryProgressed {
+                result: kad::QueryResult::PutRecord(Err(e)),
+                ..
+            } => {
+                tracing::debug!("Failed to replicate DHT record: {e:?}");
+            }
+            event => tracing::debug!(?event, "DHT event"),
+        }
+    }
+
+    /// Adds what a DHT record tells about the lookup `query` belongs to.
+    fn handle_dht_record(&mut self, query: &DhtQuery, record: &kad::Record) {
+        let (username, peer, addresses) = match query {
+            DhtQuery::Username(username) => match dht::decode_username_record(username, record) {
+                Ok(record) => (username, record.peer(), Vec::new()),
+                Err(e) => {
+                    tracing::debug!(%username, "Ignoring username record from the DHT: {e}");
+                    return;
+                }
+            },
+            DhtQuery::Addresses { username, peer } => match dht::decode_peer_record(peer, record) {
+                Some(addresses) => (username, *peer, addresses),
+                None => {
+                    tracing::debug!(%peer, "Ignoring peer record from the DHT");
+                    return;
+                }
+            },
+        };
+        if let Some(pending) = self.pending_lookups.get_mut(username) {
+            pending.holders.entry(peer).or_default().extend(addresses);
+        }
+    }
+
+    /// Looks up the addresses of a single holder found in the DHT, or resolves the lookup.
+    fn dht_query_finished(&mut self, query: DhtQuery) {
+        let username = match query {
+            DhtQuery::Username(username) => username,
+            DhtQuery::Addresses { username, .. } => {
+                self.finish_lookup(&username);
+                return;
+            }
+        };
+        let Some(pending) = self.pending_lookups.get(&username) else {
+            return;
+        };
+        let mut holders = pending.holders.keys().copied();
+        if let (Some(peer), None) = (holders.next(), holders.next()) {
+            if !self.peer_addresses.contains_key(&peer) {
+                self.query_dht(DhtQuery::Addresses { username, peer });
+                return;
+            }
+            let addresses = self.addresses_of(&peer);
+            if let Some(pending) = self.pending_lookups.get_mut(&username) {
+                pending.holders.insert(peer, addresses);
+            }
+        }
+        self.finish_lookup(&username);
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Publish {
+                room,
+                kind,
+                text,
+                sender,
+            } => {
+                let result = if self.rooms.contains(&room) {
+                    let envelope =
+                        Envelope::new(kind, self.next_seq, self.record.username(), &room, text);
+                    self.next_seq += 1;
+                    let data = envelope.encode();
+                    let size = data.len();
+                    let result = self
+                        .swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .publish(room::topic(&room), data)
+                        .map(|_| ())
+                        .map_err(|e| Box::new(e) as Box<dyn Error + Send>);
+                    if result.is_ok() {
+                        // Gossipsub sends it to our mesh peers, possibly over their circuits.
+                        let peers: Vec<_> = self.peers.keys().copied().collect();
+                        for peer in peers {
+                            self.circuit_traffic(peer, 0, size);
+                        }
+                    }
+                    result
+                } else {
+                    Err(Box::new(RoomError::NotJoined(room)) as Box<dyn Error + Send>)
+                };
+                let _ = sender.send(result);
+            }
+            Command::Join { room, sender } => {
+                let result = self
+                    .join_room(room)
+                    .map_err(|e| Box::new(e) as Box<dyn Error + Send>);
+                let _ = sender.send(result);
+            }
+            Command::Leave { room, sender } => {
+                let result = self
+                    .leave_room(room)
+                    .map_err(|e| Box::new(e) as Box<dyn Error + Send>);
+                let _ = sender.send(result);
+            }
+            Command::Rooms { sender } => {
+                let _ = sender.send(self.rooms.clone());
+            }
+            Command::Lookup { username, sender } => self.start_lookup(username, sender),
+            Command::Dial {
+                peer,
+                mut addresses,
+                sender,
+            } => {
+                // The relays are always worth a try, in case none of the addresses is reachable.
+                addresses.extend(
+                    self.circuit_addresses()
+                        .into_iter()
+                        .map(|address| address.with(Protocol::P2p(peer))),
+                );
+                tracing::info!(%peer, ?addresses, "Dialing");
+                let result = self
+                    .dial_peer(peer, addresses, PeerCondition::Disconnected)
+                    .map_err(|e| Box::new(e) as Box<dyn Error + Send>);
+                let _ = sender.send(result);
+            }
+            Command::Peers { sender } => {
+                let peers = self
+                    .peers
+                    .iter()
+                    .filter_map(|(peer, state)| Some((*peer, state.kind()?)))
+                    .collect();
+                let _ = sender.send(peers);
+            }
+        }
+    }
+}
+
+/// Splits the peer id off an address of a `kind` server, which must end in `/p2p/<peer-id>`.
+fn with_known_peer(address: Multiaddr, kind: &str) -> Result<(PeerId, Multiaddr), Box<dyn Error>> {
+    match address.iter().last() {
+        Some(Protocol::P2p(peer)) => Ok((peer, address)),
+        _ => Err(format!("The {kind} address must end with /p2p/<peer-id>.").into()),
+    }
+}
+
+/// Turns a failed relay request into a message that tells the user what to do about it.
+fn denial_reason<E: Error + 'static>(error: &StreamUpgradeError<E>) -> String {
+    match error {
+        StreamUpgradeError::Apply(reason) => {
+            // libp2p-relay keeps its reason types private, so tell them apart by name. A hermes
+            // relay refuses peers it does not admit as exceeding a resource limit.
+            let access_denied = matches!(
+                format!("{reason:?}").as_str(),
+                "PermissionDenied" | "Refused" | "ResourceLimitExceeded"
+            );
+            let reason = reason.to_string();
+            let reason = reason.trim_end_matches('.');
+            if access_denied {
+                format!(
+                    "{reason}. The relay only serves allowlisted or invited peers within their \
+                     quota, ask its operator for an invite token."
+                )
+            } else {
+                format!("{reason}.")
+            }
+        }
+        StreamUpgradeError::Timeout => "the relay did not answer in time.".to_string(),
+        StreamUpgradeError::NegotiationFailed => "the peer is not a relay.".to_string(),
+        StreamUpgradeError::Io(e) => format!("connection error: {e}"),
+    }
+}
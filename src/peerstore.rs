@@ -0,0 +1,294 @@
+//! Peers we have been connected to, kept on disk so a restarted node can dial its contacts again
+//! without going through a rendezvous server or a username lookup first.
+//!
+//! For every peer the store keeps the direct and relayed addresses it was last reachable at, the
+//! protocols it announced via identify, when we last saw it and whether hole punching to it
+//! worked.
+
+use std::{
+    cmp::Reverse,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use libp2p::{identify, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::{keystore, lookup::MAX_ADDRESSES, relay::is_relayed};
+
+/// Default location of the peerstore of `peer`, one per identity so that several local nodes do
+/// not share one.
+pub fn default_path(peer: &PeerId) -> PathBuf {
+    keystore::data_dir().join("peers").join(peer.to_string())
+}
+
+/// What we know about a peer.
+#[derive(Debug, Clone)]
+pub struct KnownPeer {
+    /// Addresses the peer can be dialed at without a relay, the most recent first.
+    pub direct_addresses: Vec<Multiaddr>,
+    /// Relay circuit addresses of the peer, the most recent first.
+    pub relayed_addresses: Vec<Multiaddr>,
+    pub protocols: Vec<String>,
+    pub last_seen: SystemTime,
+    /// Whether the last DCUtR hole punch to the peer succeeded, if we tried one.
+    pub hole_punched: Option<bool>,
+}
+
+impl Default for KnownPeer {
+    fn default() -> Self {
+        Self {
+            direct_addresses: Vec::new(),
+            relayed_addresses: Vec::new(),
+            protocols: Vec::new(),
+            last_seen: SystemTime::UNIX_EPOCH,
+            hole_punched: None,
+        }
+    }
+}
+
+impl KnownPeer {
+    /// Remembers `address` as the most recent one, direct or relayed.
+    fn add_address(&mut self, address: Multiaddr) {
+        let addresses = if is_relayed(&address) {
+            &mut self.relayed_addresses
+        } else {
+            &mut self.direct_addresses
+        };
+        addresses.retain(|known| *known != address);
+        addresses.insert(0, address);
+        addresses.truncate(MAX_ADDRESSES);
+    }
+}
+
+/// A [`KnownPeer`] as stored in the database, keyed by peer ID.
+#[derive(Serialize, Deserialize)]
+struct StoredPeer {
+    direct_addresses: Vec<Vec<u8>>,
+    relayed_addresses: Vec<Vec<u8>>,
+    protocols: Vec<String>,
+    /// Seconds since the Unix epoch.
+    last_seen: u64,
+    hole_punched: Option<bool>,
+}
+
+impl From<&KnownPeer> for StoredPeer {
+    fn from(peer: &KnownPeer) -> Self {
+        Self {
+            direct_addresses: peer.direct_addresses.iter().map(|a| a.to_vec()).collect(),
+            relayed_addresses: peer.relayed_addresses.iter().map(|a| a.to_vec()).collect(),
+            protocols: peer.protocols.clone(),
+            last_seen: peer
+                .last_seen
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            hole_punched: peer.hole_punched,
+        }
+    }
+}
+
+impl KnownPeer {
+    fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::new();
+        ciborium::into_writer(&StoredPeer::from(self), &mut value)
+            .expect("writing to a Vec not to fail");
+        value
+    }
+
+    /// Decodes a stored peer. `None` if the entry is corrupt, including a `last_seen` too far
+    /// in the future to represent.
+    fn decode(value: &[u8]) -> Option<Self> {
+        let stored = ciborium::from_reader::<StoredPeer, _>(value).ok()?;
+        let decode = |addresses: Vec<Vec<u8>>| {
+            addresses
+                .into_iter()
+                .filter_map(|address| Multiaddr::try_from(address).ok())
+                .collect()
+        };
+        Some(Self {
+            direct_addresses: decode(stored.direct_addresses),
+            relayed_addresses: decode(stored.relayed_addresses),
+            protocols: stored.protocols,
+            last_seen: SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(stored.last_seen))?,
+            hole_punched: stored.hole_punched,
+        })
+    }
+}
+
+/// The known peers, on disk.
+pub struct Peerstore {
+    db: sled::Db,
+}
+
+impl Peerstore {
+    pub fn open(path: &Path) -> sled::Result<Self> {
+        let peerstore = Self {
+            db: sled::open(path)?,
+        };
+        tracing::info!(path=%path.display(), peers = peerstore.db.len(), "Opened peerstore");
+        Ok(peerstore)
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<KnownPeer> {
+        let value = self.db.get(peer.to_bytes()).ok()??;
+        KnownPeer::decode(&value)
+    }
+
+    /// Peers seen within `max_age`, the most recently seen first.
+    pub fn recent(&self, max_age: Duration) -> Vec<(PeerId, KnownPeer)> {
+        let now = SystemTime::now();
+        let mut peers: Vec<_> = self
+            .db
+            .iter()
+            .filter_map(Result::ok)
+            .filter_map(|(key, value)| {
+                let peer = PeerId::from_bytes(&key).ok()?;
+                Some((peer, KnownPeer::decode(&value)?))
+            })
+            .filter(|(_, known)| {
+                now.duration_since(known.last_seen)
+                    .is_ok_and(|age| age <= max_age)
+            })
+            .collect();
+        peers.sort_by_key(|(_, known)| Reverse(known.last_seen));
+        peers
+    }
+
+    /// Records that we are connected to `peer`, at `address` if we dialed it.
+    pub fn connected(&self, peer: PeerId, address: Option<&Multiaddr>) {
+        self.update(peer, |known| {
+            if let Some(address) = address {
+                known.add_address(address.clone());
+            }
+        });
+    }
+
+    /// Records the listen addresses and protocols `peer` announced.
+    pub fn identified(&self, peer: PeerId, info: &identify::Info) {
+        self.update(peer, |known| {
+            // Add the first listen address last, so it ends up first.
+            for address in info.listen_addrs.iter().take(MAX_ADDRESSES).rev() {
+                known.add_address(address.clone());
+            }
+            known.protocols = info.protocols.iter().map(ToString::to_string).collect();
+        });
+    }
+
+    /// Records whether a hole punch to `peer` succeeded.
+    pub fn hole_punched(&self, peer: PeerId, succeeded: bool) {
+        self.update(peer, |known| known.hole_punched = Some(succeeded));
+    }
+
+    /// Applies `change` to what we know about `peer` and marks it as seen now.
+    fn update(&self, peer: PeerId, change: impl FnOnce(&mut KnownPeer)) {
+        let mut known = self.get(&peer).unwrap_or_default();
+        change(&mut known);
+        known.last_seen = SystemTime::now();
+
+        if let Err(e) = self.db.insert(peer.to_bytes(), known.encode()) {
+            tracing::error!(%peer, "Failed to store peer: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    fn temporary() -> Peerstore {
+        Peerstore {
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        }
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let relay = PeerId::random();
+        let known = KnownPeer {
+            direct_addresses: vec![address("/ip4/192.0.2.1/tcp/4001")],
+            relayed_addresses: vec![address(&format!(
+                "/ip4/198.51.100.1/tcp/4001/p2p/{relay}/p2p-circuit"
+            ))],
+            protocols: vec!["/meshsub/1.1.0".to_string()],
+            last_seen: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            hole_punched: Some(true),
+        };
+
+        let decoded = KnownPeer::decode(&known.encode()).unwrap();
+        assert_eq!(decoded.direct_addresses, known.direct_addresses);
+        assert_eq!(decoded.relayed_addresses, known.relayed_addresses);
+        assert_eq!(decoded.protocols, known.protocols);
+        assert_eq!(decoded.last_seen, known.last_seen);
+        assert_eq!(decoded.hole_punched, known.hole_punched);
+    }
+
+    #[test]
+    fn corrupt_entries_are_skipped() {
+        let stored = StoredPeer {
+            direct_addresses: Vec::new(),
+            relayed_addresses: Vec::new(),
+            protocols: Vec::new(),
+            last_seen: u64::MAX,
+            hole_punched: None,
+        };
+        let mut value = Vec::new();
+        ciborium::into_writer(&stored, &mut value).unwrap();
+        assert!(KnownPeer::decode(&value).is_none());
+        assert!(KnownPeer::decode(b"not cbor").is_none());
+
+        let peerstore = temporary();
+        let peer = PeerId::random();
+        peerstore.db.insert(peer.to_bytes(), value).unwrap();
+        assert!(peerstore.get(&peer).is_none());
+        assert!(peerstore.recent(Duration::MAX).is_empty());
+    }
+
+    #[test]
+    fn direct_addresses_are_kept_apart_from_relayed_ones() {
+        let relay = PeerId::random();
+        let relayed = address(&format!(
+            "/ip4/198.51.100.1/tcp/4001/p2p/{relay}/p2p-circuit"
+        ));
+        let older = address("/ip4/192.0.2.1/tcp/4001");
+        let newer = address("/ip4/192.0.2.2/tcp/4001");
+
+        let peerstore = temporary();
+        let peer = PeerId::random();
+        peerstore.connected(peer, Some(&relayed));
+        peerstore.connected(peer, Some(&older));
+        peerstore.connected(peer, Some(&newer));
+
+        let known = peerstore.get(&peer).unwrap();
+        assert_eq!(known.direct_addresses, vec![newer, older]);
+        assert_eq!(known.relayed_addresses, vec![relayed]);
+    }
+
+    #[test]
+    fn recent_peers_come_most_recent_first() {
+        let peerstore = temporary();
+        let now = SystemTime::now();
+        let (older, newer, stale) = (PeerId::random(), PeerId::random(), PeerId::random());
+        for (peer, age) in [(older, 20), (newer, 10), (stale, 120)] {
+            let known = KnownPeer {
+                last_seen: now - Duration::from_secs(age),
+                ..Default::default()
+            };
+            peerstore
+                .db
+                .insert(peer.to_bytes(), known.encode())
+                .unwrap();
+        }
+
+        let recent: Vec<_> = peerstore
+            .recent(Duration::from_secs(60))
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect();
+        assert_eq!(recent, vec![newer, older]);
+    }
+}
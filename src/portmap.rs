@@ -0,0 +1,487 @@
+//! Port mapping on a gateway we are told about, for routers libp2p's UPnP behaviour cannot use:
+//! one that only speaks NAT-PMP, or a UPnP IGD at a known address, such as a local stand-in for
+//! testing. The UPnP behaviour searches the network for a gateway and only accepts one with a
+//! public address. Here the gateway is given, so whatever external address it reports is used.
+//!
+//! Every TCP and QUIC listen address on IPv4 gets the same port mapped on the gateway, renewed
+//! at half of its lease. A mapping that cannot be renewed is reported as expired and retried.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use futures::{channel::mpsc, future::Either};
+use igd_next::{PortMappingProtocol, SearchOptions};
+use libp2p::{core::multiaddr::Protocol, Multiaddr};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+/// Port NAT-PMP gateways listen on.
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// How long we ask the gateway to keep a mapping unless renewed.
+const LEASE: Duration = Duration::from_secs(60 * 60);
+
+/// Shortest time between renewals, however short a lease the gateway grants.
+const MIN_RENEWAL: Duration = Duration::from_secs(10);
+
+/// How long to wait before mapping again after it failed.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How often a NAT-PMP request is sent before giving up. The wait for an answer starts at 250
+/// ms and doubles with every attempt, as RFC 6886 asks.
+const NAT_PMP_ATTEMPTS: u32 = 6;
+
+/// Description of our mappings on a UPnP gateway.
+const DESCRIPTION: &str = "hermes";
+
+type MapError = Box<dyn Error + Send + Sync>;
+
+/// The gateway to map ports on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gateway {
+    /// A UPnP IGD answering SSDP searches at this address.
+    Upnp(SocketAddr),
+    /// A router speaking NAT-PMP at this address, usually on [`NAT_PMP_PORT`].
+    NatPmp(SocketAddr),
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The gateway forwards `external` to our listen address `local`.
+    Mapped {
+        local: Multiaddr,
+        external: Multiaddr,
+    },
+    /// The mapping of `external` could not be renewed, so peers can no longer dial it.
+    Expired { external: Multiaddr },
+    /// Mapping `local` failed. Retried after a while.
+    Failed { local: Multiaddr, reason: String },
+}
+
+/// Keeps a mapping on the gateway for every listen address that can have one.
+pub struct PortMapper {
+    gateway: Gateway,
+    events: mpsc::UnboundedSender<Event>,
+    mappings: HashMap<Multiaddr, JoinHandle<()>>,
+}
+
+impl PortMapper {
+    /// A mapper for `gateway`, with the stream of its events. Mapping runs on the current tokio
+    /// runtime.
+    pub fn new(gateway: Gateway) -> (Self, mpsc::UnboundedReceiver<Event>) {
+        let (events, receiver) = mpsc::unbounded();
+        let mapper = Self {
+            gateway,
+            events,
+            mappings: HashMap::new(),
+        };
+        (mapper, receiver)
+    }
+
+    /// Starts mapping the listen address `address`, if it is a TCP or QUIC address on IPv4
+    /// that other hosts can reach.
+    pub fn add(&mut self, address: &Multiaddr) {
+        let Some((protocol, local)) = mappable(address) else {
+            return;
+        };
+        if self.mappings.contains_key(address) {
+            return;
+        }
+        tracing::info!(%address, gateway=?self.gateway, "Mapping port");
+        let task = tokio::spawn(keep_mapped(
+            self.gateway,
+            protocol,
+            local,
+            address.clone(),
+            self.events.clone(),
+        ));
+        self.mappings.insert(address.clone(), task);
+    }
+
+    /// Stops renewing the mapping of `address`. The gateway drops it once its lease ends.
+    pub fn remove(&mut self, address: &Multiaddr) {
+        if let Some(task) = self.mappings.remove(address) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        for task in self.mappings.values() {
+            task.abort();
+        }
+    }
+}
+
+/// The transport protocol and local socket of `address`, if it can be mapped.
+fn mappable(address: &Multiaddr) -> Option<(PortMappingProtocol, SocketAddrV4)> {
+    let mut protocols = address.iter();
+    let ip = match protocols.next()? {
+        Protocol::Ip4(ip) if !ip.is_loopback() && !ip.is_unspecified() => ip,
+        _ => return None,
+    };
+    let (protocol, port) = match (protocols.next()?, protocols.next(), protocols.next()) {
+        (Protocol::Tcp(port), None, _) => (PortMappingProtocol::TCP, port),
+        (Protocol::Udp(port), Some(Protocol::QuicV1), None) => (PortMappingProtocol::UDP, port),
+        _ => return None,
+    };
+    Some((protocol, SocketAddrV4::new(ip, port)))
+}
+
+/// `local` with the IP address and port of `external`.
+fn external_address(local: &Multiaddr, external: SocketAddrV4) -> Multiaddr {
+    local
+        .iter()
+        .map(|protocol| match protocol {
+            Protocol::Ip4(_) => Protocol::Ip4(*external.ip()),
+            Protocol::Tcp(_) => Protocol::Tcp(external.port()),
+            Protocol::Udp(_) => Protocol::Udp(external.port()),
+            protocol => protocol,
+        })
+        .collect()
+}
+
+/// Maps `local` on `gateway` and keeps renewing the mapping until aborted.
+async fn keep_mapped(
+    gateway: Gateway,
+    protocol: PortMappingProtocol,
+    local: SocketAddrV4,
+    address: Multiaddr,
+    events: mpsc::UnboundedSender<Event>,
+) {
+    let mut mapped: Option<Multiaddr> = None;
+    loop {
+        let delay = match map(gateway, protocol, local).await {
+            Ok((external, lease)) => {
+                let external = external_address(&address, external);
+                if mapped.as_ref() != Some(&external) {
+                    if let Some(expired) = mapped.replace(external.clone()) {
+                        let _ = events.unbounded_send(Event::Expired { external: expired });
+                    }
+                    let _ = events.unbounded_send(Event::Mapped {
+                        local: address.clone(),
+                        external,
+                    });
+                }
+                (lease / 2).max(MIN_RENEWAL)
+            }
+            Err(e) => {
+                if let Some(expired) = mapped.take() {
+                    let _ = events.unbounded_send(Event::Expired { external: expired });
+                }
+                let _ = events.unbounded_send(Event::Failed {
+                    local: address.clone(),
+                    reason: e.to_string(),
+                });
+                RETRY_DELAY
+            }
+        };
+        futures_timer::Delay::new(delay).await;
+    }
+}
+
+/// Asks `gateway` to forward a port on its external address to `local`. Returns the external
+/// socket and how long the gateway keeps the mapping.
+async fn map(
+    gateway: Gateway,
+    protocol: PortMappingProtocol,
+    local: SocketAddrV4,
+) -> Result<(SocketAddrV4, Duration), MapError> {
+    match gateway {
+        Gateway::Upnp(address) => map_upnp(address, protocol, local).await,
+        Gateway::NatPmp(address) => map_nat_pmp(address, protocol, local).await,
+    }
+}
+
+async fn map_upnp(
+    address: SocketAddr,
+    protocol: PortMappingProtocol,
+    local: SocketAddrV4,
+) -> Result<(SocketAddrV4, Duration), MapError> {
+    let options = SearchOptions {
+        broadcast_address: address,
+        ..Default::default()
+    };
+    let gateway = igd_next::aio::tokio::search_gateway(options).await?;
+    gateway
+        .add_port(
+            protocol,
+            local.port(),
+            SocketAddr::V4(local),
+            LEASE.as_secs() as u32,
+            DESCRIPTION,
+        )
+        .await?;
+    match gateway.get_external_ip().await? {
+        IpAddr::V4(ip) => Ok((SocketAddrV4::new(ip, local.port()), LEASE)),
+        IpAddr::V6(ip) => Err(format!("gateway reports the IPv6 external address {ip}").into()),
+    }
+}
+
+async fn map_nat_pmp(
+    gateway: SocketAddr,
+    protocol: PortMappingProtocol,
+    local: SocketAddrV4,
+) -> Result<(SocketAddrV4, Duration), MapError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+
+    let response = nat_pmp_request(&socket, &[0, 0], 12).await?;
+    let ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+    let mut request = [0; 12];
+    request[1] = match protocol {
+        PortMappingProtocol::UDP => 1,
+        PortMappingProtocol::TCP => 2,
+    };
+    request[4..6].copy_from_slice(&local.port().to_be_bytes());
+    request[6..8].copy_from_slice(&local.port().to_be_bytes());
+    request[8..12].copy_from_slice(&(LEASE.as_secs() as u32).to_be_bytes());
+    let response = nat_pmp_request(&socket, &request, 16).await?;
+    let port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((
+        SocketAddrV4::new(ip, port),
+        Duration::from_secs(lifetime.into()),
+    ))
+}
+
+/// Sends the NAT-PMP `request` until the gateway answers it with a response of `len` bytes.
+async fn nat_pmp_request(
+    socket: &UdpSocket,
+    request: &[u8],
+    len: usize,
+) -> Result<Vec<u8>, MapError> {
+    let mut wait = Duration::from_millis(250);
+    let mut buf = [0; 16];
+    for _ in 0..NAT_PMP_ATTEMPTS {
+        socket.send(request).await?;
+        let received = {
+            let recv = Box::pin(socket.recv(&mut buf));
+            match futures::future::select(recv, futures_timer::Delay::new(wait)).await {
+                Either::Left((received, _)) => received?,
+                Either::Right(_) => {
+                    wait *= 2;
+                    continue;
+                }
+            }
+        };
+        // Version 0, and the opcode of the request with its high bit set.
+        if received < len || buf[0] != 0 || buf[1] != request[1] | 0x80 {
+            continue;
+        }
+        return match u16::from_be_bytes([buf[2], buf[3]]) {
+            0 => Ok(buf[..len].to_vec()),
+            code => Err(format!("NAT-PMP gateway refused: {}", nat_pmp_error(code)).into()),
+        };
+    }
+    Err("NAT-PMP gateway did not answer".into())
+}
+
+/// The meaning of a NAT-PMP result code, from RFC 6886, section 3.5.
+fn nat_pmp_error(code: u16) -> &'static str {
+    match code {
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown result code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn only_reachable_tcp_and_quic_addresses_are_mapped() {
+        assert_eq!(
+            mappable(&address("/ip4/192.168.1.5/tcp/4001")),
+            Some((
+                PortMappingProtocol::TCP,
+                "192.168.1.5:4001".parse().unwrap()
+            ))
+        );
+        assert_eq!(
+            mappable(&address("/ip4/192.168.1.5/udp/4001/quic-v1")),
+            Some((
+                PortMappingProtocol::UDP,
+                "192.168.1.5:4001".parse().unwrap()
+            ))
+        );
+        assert_eq!(mappable(&address("/ip4/127.0.0.1/tcp/4001")), None);
+        assert_eq!(mappable(&address("/ip6/::1/tcp/4001")), None);
+        assert_eq!(mappable(&address("/ip4/192.168.1.5/tcp/4001/ws")), None);
+    }
+
+    #[test]
+    fn external_address_keeps_the_transport() {
+        assert_eq!(
+            external_address(
+                &address("/ip4/192.168.1.5/udp/4001/quic-v1"),
+                "203.0.113.7:5001".parse().unwrap()
+            ),
+            address("/ip4/203.0.113.7/udp/5001/quic-v1")
+        );
+    }
+
+    /// Answers one external address and one mapping request the way a NAT-PMP gateway does.
+    async fn fake_nat_pmp_gateway(socket: UdpSocket) {
+        let mut buf = [0; 12];
+        for _ in 0..2 {
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let mut response = vec![0, buf[1] | 0x80, 0, 0, 0, 0, 0, 1];
+            match buf[1] {
+                0 => response.extend([203, 0, 113, 7]),
+                _ => {
+                    response.extend(&buf[4..6]);
+                    response.extend(5001u16.to_be_bytes());
+                    response.extend(7200u32.to_be_bytes());
+                }
+            }
+            socket.send_to(&response, peer).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn maps_a_port_with_nat_pmp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = Gateway::NatPmp(socket.local_addr().unwrap());
+        tokio::spawn(fake_nat_pmp_gateway(socket));
+
+        let (external, lease) = map(
+            gateway,
+            PortMappingProtocol::TCP,
+            "192.168.1.5:4001".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(external, "203.0.113.7:5001".parse().unwrap());
+        assert_eq!(lease, Duration::from_secs(7200));
+    }
+
+    const ROOT_DESCRIPTION: &str = "<?xml version=\"1.0\"?>\
+        <root><device><serviceList><service>\
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <SCPDURL>/scpd.xml</SCPDURL><controlURL>/control</controlURL>\
+        </service></serviceList></device></root>";
+
+    const SERVICE_DESCRIPTION: &str = "<?xml version=\"1.0\"?>\
+        <scpd><actionList>\
+        <action><name>AddPortMapping</name><argumentList>\
+        <argument><name>NewRemoteHost</name><direction>in</direction></argument>\
+        <argument><name>NewExternalPort</name><direction>in</direction></argument>\
+        <argument><name>NewProtocol</name><direction>in</direction></argument>\
+        <argument><name>NewInternalPort</name><direction>in</direction></argument>\
+        <argument><name>NewInternalClient</name><direction>in</direction></argument>\
+        <argument><name>NewEnabled</name><direction>in</direction></argument>\
+        <argument><name>NewPortMappingDescription</name><direction>in</direction></argument>\
+        <argument><name>NewLeaseDuration</name><direction>in</direction></argument>\
+        </argumentList></action>\
+        <action><name>GetExternalIPAddress</name><argumentList>\
+        <argument><name>NewExternalIPAddress</name><direction>out</direction></argument>\
+        </argumentList></action>\
+        </actionList></scpd>";
+
+    fn soap_response(action: &str, body: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+            <u:{action}Response xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+            {body}</u:{action}Response></s:Body></s:Envelope>"
+        )
+    }
+
+    /// Serves the descriptions and answers the SOAP actions of a UPnP gateway, one request per
+    /// connection, and returns the actions in the order they were requested.
+    fn fake_igd(listener: std::net::TcpListener) -> Vec<String> {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let mut actions = Vec::new();
+        while actions.len() < 2 {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let (mut length, mut action) = (0, None);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let Some((name, value)) = line.trim_end().split_once(':') else {
+                    break;
+                };
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => length = value.trim().parse().unwrap(),
+                    "soapaction" => {
+                        let (_, action_name) =
+                            value.trim().trim_matches('"').split_once('#').unwrap();
+                        action = Some(action_name.to_owned());
+                    }
+                    _ => {}
+                }
+            }
+            reader.read_exact(&mut vec![0; length]).unwrap();
+
+            let body = match (request.split(' ').nth(1), action.as_deref()) {
+                (Some("/rootDesc.xml"), _) => ROOT_DESCRIPTION.to_owned(),
+                (Some("/scpd.xml"), _) => SERVICE_DESCRIPTION.to_owned(),
+                (Some("/control"), Some("AddPortMapping")) => soap_response("AddPortMapping", ""),
+                (Some("/control"), Some("GetExternalIPAddress")) => soap_response(
+                    "GetExternalIPAddress",
+                    "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+                ),
+                _ => panic!("unexpected request {request:?} for {action:?}"),
+            };
+            actions.extend(action);
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        }
+        actions
+    }
+
+    #[tokio::test]
+    async fn maps_a_port_with_upnp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let location = listener.local_addr().unwrap();
+        let igd = std::thread::spawn(move || fake_igd(listener));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = Gateway::Upnp(socket.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                LOCATION: http://{location}/rootDesc.xml\r\n\r\n"
+            );
+            socket.send_to(response.as_bytes(), peer).await.unwrap();
+        });
+
+        let (external, lease) = map(
+            gateway,
+            PortMappingProtocol::TCP,
+            "192.168.1.5:4001".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(external, "203.0.113.7:4001".parse().unwrap());
+        assert_eq!(lease, LEASE);
+        assert_eq!(
+            igd.join().unwrap(),
+            ["AddPortMapping", "GetExternalIPAddress"]
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! A circuit relay v2 server that clients reserve slots on and hole punch through.
+//!
+//! Unless started with `--open`, only peers admitted by [`AccessControl`] may use the relay.
+
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::Args;
+use futures::stream::StreamExt;
+use libp2p::{
+    autonat, core::multiaddr::Protocol, identify, noise, ping, relay, request_response,
+    swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux, Multiaddr, PeerId,
+};
+
+use crate::{
+    access::{self, AccessControl, InviteCodec, InviteResponse, InviteToken, Quota},
+    behaviour::PROTOCOL_VERSION,
+    cli::IdentityOpts,
+};
+
+#[derive(Debug, Args)]
+pub struct Opts {
+    #[command(flatten)]
+    pub identity: IdentityOpts,
+
+    /// Address to listen on. May be given multiple times.
+    #[arg(
+        long = "listen-address",
+        default_values = ["/ip4/0.0.0.0/tcp/4001", "/ip4/0.0.0.0/udp/4001/quic-v1"]
+    )]
+    pub listen_addresses: Vec<Multiaddr>,
+
+    /// Publicly reachable address of this relay, announced to clients. May be given multiple
+    /// times.
+    #[arg(long = "external-address")]
+    pub external_addresses: Vec<Multiaddr>,
+
+    /// Maximum number of reservations held at the same time.
+    #[arg(long, default_value_t = 128)]
+    pub max_reservations: usize,
+
+    /// Maximum number of reservations held by a single peer.
+    #[arg(long, default_value_t = 4)]
+    pub max_reservations_per_peer: usize,
+
+    /// How long a reservation stays valid before the client has to renew it, in seconds.
+    #[arg(long, default_value_t = 3600)]
+    pub reservation_duration_secs: u64,
+
+    /// Maximum number of circuits relayed at the same time.
+    #[arg(long, default_value_t = 16)]
+    pub max_circuits: usize,
+
+    /// Maximum number of circuits relayed for a single peer.
+    #[arg(long, default_value_t = 4)]
+    pub max_circuits_per_peer: usize,
+
+    /// How long a single circuit may stay open, in seconds.
+    #[arg(long, default_value_t = 120)]
+    pub max_circuit_duration_secs: u64,
+
+    /// How many bytes may be relayed in each direction of a single circuit.
+    #[arg(long, default_value_t = 1 << 17)]
+    pub max_circuit_bytes: u64,
+
+    /// Let any peer reserve slots and open circuits. Makes this an open relay for the internet.
+    #[arg(long, conflicts_with_all = ["allow_peers", "allowlist"])]
+    pub open: bool,
+
+    /// Peer allowed to use the relay with the default quota. May be given multiple times.
+    #[arg(long = "allow-peer")]
+    pub allow_peers: Vec<PeerId>,
+
+    /// File listing the peers allowed to use the relay, one per line, with optional quota
+    /// overrides such as `circuits=10 bytes=1048576 lifetime=86400`.
+    #[arg(long)]
+    pub allowlist: Option<PathBuf>,
+
+    /// Default number of circuits an admitted peer may open per hour.
+    #[arg(long, default_value_t = 60)]
+    pub quota_circuits_per_hour: u32,
+
+    /// Default number of bytes an admitted peer may relay per hour. Every circuit counts with
+    /// its full `--max-circuit-bytes`.
+    #[arg(long, default_value_t = 64 << 20)]
+    pub quota_bytes_per_hour: u64,
+
+    /// Default time after its first reservation during which an admitted peer may keep
+    /// reserving, in seconds. Unlimited if not set.
+    #[arg(long)]
+    pub quota_reservation_lifetime_secs: Option<u64>,
+}
+
+/// Issues an invite token that lets one peer use this relay.
+#[derive(Debug, Args)]
+pub struct InviteOpts {
+    /// Identity of the relay the token is for.
+    #[command(flatten)]
+    pub identity: IdentityOpts,
+
+    /// Peer the token is issued to.
+    #[arg(long)]
+    pub peer: PeerId,
+
+    /// How long the token stays valid, in seconds.
+    #[arg(long, default_value_t = 7 * 24 * 60 * 60)]
+    pub valid_for_secs: u64,
+}
+
+impl Opts {
+    fn access_control(&self) -> Result<AccessControl, Box<dyn Error>> {
+        let quota = Quota {
+            circuits_per_hour: self.quota_circuits_per_hour,
+            bytes_per_hour: self.quota_bytes_per_hour,
+            reservation_lifetime: self
+                .quota_reservation_lifetime_secs
+                .map(Duration::from_secs),
+        };
+
+        let mut access = AccessControl::new(quota, self.max_circuit_bytes);
+        for peer in &self.allow_peers {
+            access.allow(*peer, None);
+        }
+        if let Some(path) = &self.allowlist {
+            access.load_allowlist(path)?;
+        }
+
+        Ok(access)
+    }
+
+    fn relay_config(&self) -> relay::Config {
+        relay::Config {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            reservation_duration: Duration::from_secs(self.reservation_duration_secs),
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: Duration::from_secs(self.max_circuit_duration_secs),
+            max_circuit_bytes: self.max_circuit_bytes,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    relay: relay::Behaviour,
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+    invite: request_response::Behaviour<InviteCodec>,
+    /// Dials clients back, so they learn whether they are reachable.
+    autonat: autonat::Behaviour,
+}
+
+pub async fn run(opts: Opts) -> Result<(), Box<dyn Error>> {
+    if opts.max_circuit_duration_secs > u64::from(u32::MAX) {
+        return Err("--max-circuit-duration-secs may not exceed u32::MAX".into());
+    }
+    let keypair = opts.identity.keypair()?;
+    let relay_public_key = keypair.public();
+
+    let mut relay_config = opts.relay_config();
+    let access = Arc::new(Mutex::new(opts.access_control()?));
+    if opts.open {
+        tracing::warn!("Running an open relay, any peer may reserve slots and open circuits");
+    } else {
+        // Run after the default limiters so quota is only consumed by requests that pass them.
+        relay_config
+            .reservation_rate_limiters
+            .push(AccessControl::reservation_limiter(access.clone()));
+        relay_config
+            .circuit_src_rate_limiters
+            .push(AccessControl::circuit_limiter(access.clone()));
+    }
+
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default().nodelay(true),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_behaviour(|key| Behaviour {
+            relay: relay::Behaviour::new(key.public().to_peer_id(), relay_config),
+            ping: ping::Behaviour::new(ping::Config::new()),
+            identify: identify::Behaviour::new(identify::Config::new(
+                PROTOCOL_VERSION.to_string(),
+                key.public(),
+            )),
+            invite: request_response::Behaviour::new(
+                [(
+                    access::INVITE_PROTOCOL,
+                    request_response::ProtocolSupport::Inbound,
+                )],
+                request_response::Config::default(),
+            ),
+            autonat: autonat::Behaviour::new(key.public().to_peer_id(), Default::default()),
+        })?
+        .build();
+
+    for address in opts.listen_addresses {
+        swarm.listen_on(address)?;
+    }
+
+    let local_peer_id = *swarm.local_peer_id();
+    for address in opts.external_addresses {
+        println!(
+            "Clients should use --relay-address {}",
+            with_peer_id(address.clone(), local_peer_id)
+        );
+        swarm.add_external_address(address);
+    }
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                println!("Listening on {}", with_peer_id(address, local_peer_id));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(event)) => {
+                tracing::info!(?event, "Relay event");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(event)) => {
+                tracing::debug!(?event, "AutoNAT event");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Invite(request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+            })) => {
+                let response = match access.lock().expect("not poisoned").redeem(
+                    &request,
+                    peer,
+                    &relay_public_key,
+                ) {
+                    Ok(()) => {
+                        tracing::info!(%peer, "Admitted peer with invite token");
+                        InviteResponse::Accepted
+                    }
+                    Err(e) => {
+                        tracing::info!(%peer, "Rejected invite token: {e}");
+                        InviteResponse::Rejected(e.to_string())
+                    }
+                };
+                let _ = swarm
+                    .behaviour_mut()
+                    .invite
+                    .send_response(channel, response);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => {
+                tracing::debug!(peer=%peer_id, observed=%info.observed_addr, "Identified peer");
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                tracing::info!(peer=%peer_id, ?endpoint, "Established new connection");
+            }
+            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                tracing::info!(peer=%peer_id, ?cause, "Connection closed");
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn invite(opts: InviteOpts) -> Result<(), Box<dyn Error>> {
+    let keypair = opts.identity.keypair()?;
+    let token = InviteToken::issue(
+        &keypair,
+        opts.peer,
+        Duration::from_secs(opts.valid_for_secs),
+    )
+    .ok_or("--valid-for-secs is too long.")?;
+    println!("{token}");
+
+    Ok(())
+}
+
+/// Appends `/p2p/<peer_id>` to `address`, the form clients need to dial it.
+pub(crate) fn with_peer_id(address: Multiaddr, peer_id: PeerId) -> Multiaddr {
+    match address.iter().last() {
+        Some(Protocol::P2p(_)) => address,
+        _ => address.with(Protocol::P2p(peer_id)),
+    }
+}
+
+/// Whether `address` goes through a relay circuit.
+pub(crate) fn is_relayed(address: &Multiaddr) -> bool {
+    address
+        .iter()
+        .any(|protocol| protocol == Protocol::P2pCircuit)
+}
+
+/// The relay `address` goes through, if it is a circuit address naming it.
+pub(crate) fn circuit_relay(address: &Multiaddr) -> Option<PeerId> {
+    let mut relay = None;
+    for protocol in address.iter() {
+        match protocol {
+            Protocol::P2p(peer_id) => relay = Some(peer_id),
+            Protocol::P2pCircuit => return relay,
+            _ => {}
+        }
+    }
+    None
+}
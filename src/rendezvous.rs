@@ -0,0 +1,122 @@
+//! A rendezvous server that chat clients register with to find the other members of a room.
+//!
+//! Every room maps to its own namespace, see [`room_namespace`].
+
+use std::error::Error;
+
+use clap::Args;
+use futures::stream::StreamExt;
+use libp2p::{
+    identify, noise, ping,
+    rendezvous::{self, Namespace, NamespaceTooLong},
+    swarm::NetworkBehaviour,
+    swarm::SwarmEvent,
+    tcp, yamux, Multiaddr,
+};
+
+use crate::{behaviour::PROTOCOL_VERSION, cli::IdentityOpts, relay::with_peer_id};
+
+#[derive(Debug, Args)]
+pub struct Opts {
+    #[command(flatten)]
+    pub identity: IdentityOpts,
+
+    /// Address to listen on. May be given multiple times.
+    #[arg(
+        long = "listen-address",
+        default_values = ["/ip4/0.0.0.0/tcp/4002", "/ip4/0.0.0.0/udp/4002/quic-v1"]
+    )]
+    pub listen_addresses: Vec<Multiaddr>,
+
+    /// Publicly reachable address of this server, announced to clients. May be given multiple
+    /// times.
+    #[arg(long = "external-address")]
+    pub external_addresses: Vec<Multiaddr>,
+}
+
+/// The rendezvous namespace members of `room` register under.
+pub fn room_namespace(room: &str) -> Result<Namespace, NamespaceTooLong> {
+    Namespace::new(format!("hermes/room/{room}"))
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    rendezvous: rendezvous::server::Behaviour,
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+}
+
+pub async fn run(opts: Opts) -> Result<(), Box<dyn Error>> {
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(opts.identity.keypair()?)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default().nodelay(true),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_behaviour(|key| Behaviour {
+            rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
+            ping: ping::Behaviour::new(ping::Config::new()),
+            identify: identify::Behaviour::new(identify::Config::new(
+                PROTOCOL_VERSION.to_string(),
+                key.public(),
+            )),
+        })?
+        .build();
+
+    for address in opts.listen_addresses {
+        swarm.listen_on(address)?;
+    }
+
+    let local_peer_id = *swarm.local_peer_id();
+    for address in opts.external_addresses {
+        println!(
+            "Clients should use --rendezvous-address {}",
+            with_peer_id(address.clone(), local_peer_id)
+        );
+        swarm.add_external_address(address);
+    }
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                println!("Listening on {}", with_peer_id(address, local_peer_id));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(
+                rendezvous::server::Event::PeerRegistered { peer, registration },
+            )) => {
+                tracing::info!(
+                    %peer,
+                    namespace=%registration.namespace,
+                    ttl=registration.ttl,
+                    "Peer registered"
+                );
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(
+                rendezvous::server::Event::DiscoverServed {
+                    enquirer,
+                    registrations,
+                },
+            )) => {
+                tracing::info!(
+                    peer=%enquirer,
+                    count=registrations.len(),
+                    "Served discover request"
+                );
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Rendezvous(event)) => {
+                tracing::debug!(?event, "Rendezvous event");
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                tracing::info!(peer=%peer_id, ?endpoint, "Established new connection");
+            }
+            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                tracing::info!(peer=%peer_id, ?cause, "Connection closed");
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Chat rooms. Every room is a gossipsub topic in the hermes namespace, so rooms never collide
+//! with the topics of other protocols, and members of a room only receive its messages.
+
+use std::{error::Error, fmt};
+
+use libp2p::gossipsub;
+
+/// Room every client joins unless told otherwise.
+pub const DEFAULT_ROOM: &str = "general";
+
+/// Prefix of every room topic.
+const TOPIC_PREFIX: &str = "hermes/room/";
+
+/// Longest room name, leaving room for the prefix of its rendezvous namespace.
+pub const MAX_ROOM_LEN: usize = 64;
+
+/// The gossipsub topic of `room`.
+pub fn topic(room: &str) -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(format!("{TOPIC_PREFIX}{room}"))
+}
+
+/// The room `topic` belongs to, if it is a room topic at all.
+pub fn room_of(topic: &gossipsub::TopicHash) -> Option<&str> {
+    topic.as_str().strip_prefix(TOPIC_PREFIX)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomError {
+    InvalidName(String),
+    NotJoined(String),
+    AlreadyJoined(String),
+    /// Gossipsub failed to subscribe to or unsubscribe from the topic of the room.
+    Subscription(String),
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::InvalidName(room) => write!(
+                f,
+                "invalid room name {room:?}, use up to {MAX_ROOM_LEN} letters, digits, '-' or '_'"
+            ),
+            RoomError::NotJoined(room) => write!(f, "not a member of room {room}"),
+            RoomError::AlreadyJoined(room) => write!(f, "already a member of room {room}"),
+            RoomError::Subscription(error) => write!(f, "failed to update subscription: {error}"),
+        }
+    }
+}
+
+impl Error for RoomError {}
+
+/// Checks that `room` is a usable room name.
+pub fn validate(room: &str) -> Result<(), RoomError> {
+    let valid = !room.is_empty()
+        && room.len() <= MAX_ROOM_LEN
+        && room
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(RoomError::InvalidName(room.to_string()))
+    }
+}
@@ -0,0 +1,225 @@
+//! Spam protection profiles for the chat network.
+//!
+//! A profile bundles the gossipsub peer score parameters and thresholds, the score parameters of
+//! every topic we subscribe to, the rate limit authors are held to and how many messages over it
+//! get an author blacklisted, and for how long. Peers whose score drops below the thresholds are
+//! first no longer gossiped with, then no longer published to and finally ignored entirely.
+//!
+//! Gossipsub counts every message we reject against the peer that forwarded it, through the
+//! invalid message deliveries of the topic. The application specific score only counts floods,
+//! which are ignored rather than rejected, so no offence is counted twice.
+
+use std::time::Duration;
+
+use clap::ValueEnum;
+use libp2p::gossipsub::{
+    self, score_parameter_decay, PeerScoreParams, PeerScoreThresholds, TopicScoreParams,
+};
+
+use crate::validation::RateLimit;
+
+/// How hard a node pushes back on peers that misbehave.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// Tolerates bursts and forgives quickly, for small rooms among friends.
+    Relaxed,
+    #[default]
+    Standard,
+    /// Cuts off misbehaving peers quickly, for large public rooms.
+    Strict,
+}
+
+impl Profile {
+    /// Scoring across topics. The decay interval stays at its default, as every decay here is
+    /// computed against it.
+    pub fn peer_score_params(self) -> PeerScoreParams {
+        // How much each flood offence weighs, and how many broken promises and backoff
+        // violations are tolerated before they count, decaying over the given time.
+        let (app_weight, behaviour_weight, behaviour_threshold, behaviour_decay) = match self {
+            Profile::Relaxed => (1.0, -1.0, 6.0, Duration::from_secs(60)),
+            Profile::Standard => (2.0, -10.0, 2.0, Duration::from_secs(10 * 60)),
+            Profile::Strict => (5.0, -20.0, 0.0, Duration::from_secs(60 * 60)),
+        };
+        PeerScoreParams {
+            // Bound how much good behaviour in topics makes up for offences.
+            topic_score_cap: 50.0,
+            app_specific_weight: app_weight,
+            // Peers reached through the same relay all share its address.
+            ip_colocation_factor_weight: 0.0,
+            behaviour_penalty_weight: behaviour_weight,
+            behaviour_penalty_threshold: behaviour_threshold,
+            behaviour_penalty_decay: score_parameter_decay(behaviour_decay),
+            ..Default::default()
+        }
+    }
+
+    pub fn thresholds(self) -> PeerScoreThresholds {
+        let (gossip, publish, graylist) = match self {
+            Profile::Relaxed => (-20.0, -100.0, -200.0),
+            Profile::Standard => (-10.0, -50.0, -80.0),
+            Profile::Strict => (-5.0, -25.0, -40.0),
+        };
+        PeerScoreThresholds {
+            gossip_threshold: gossip,
+            publish_threshold: publish,
+            graylist_threshold: graylist,
+            ..Default::default()
+        }
+    }
+
+    /// Score parameters of each topic we subscribe to.
+    pub fn topic_params(self) -> TopicScoreParams {
+        // Gossipsub squares the count of invalid messages. A peer forwarding one is no longer
+        // gossiped with under the strict profile. Without credit for good behaviour, it is
+        // graylisted after 11, 3 and 2 of them under the relaxed, standard and strict profile.
+        let (invalid_weight, invalid_decay) = match self {
+            Profile::Relaxed => (-2.0, Duration::from_secs(60)),
+            Profile::Standard => (-10.0, Duration::from_secs(10 * 60)),
+            Profile::Strict => (-20.0, Duration::from_secs(60 * 60)),
+        };
+        TopicScoreParams {
+            topic_weight: 1.0,
+            // Up to 10 points for staying in the mesh for an hour.
+            time_in_mesh_weight: 10.0 / 3600.0,
+            time_in_mesh_quantum: Duration::from_secs(1),
+            time_in_mesh_cap: 3600.0,
+            first_message_deliveries_weight: 1.0,
+            first_message_deliveries_decay: score_parameter_decay(Duration::from_secs(10 * 60)),
+            first_message_deliveries_cap: 20.0,
+            // Rooms can be quiet for hours, so a mesh peer delivering nothing is no offence.
+            mesh_message_deliveries_weight: 0.0,
+            mesh_failure_penalty_weight: 0.0,
+            invalid_message_deliveries_weight: invalid_weight,
+            invalid_message_deliveries_decay: score_parameter_decay(invalid_decay),
+            ..Default::default()
+        }
+    }
+
+    pub fn rate_limit(self) -> RateLimit {
+        let messages = match self {
+            Profile::Relaxed => 30,
+            Profile::Standard => 10,
+            Profile::Strict => 5,
+        };
+        RateLimit {
+            messages,
+            per: Duration::from_secs(10),
+        }
+    }
+
+    /// How many messages over the rate limit get their author blacklisted.
+    pub fn flood_limit(self) -> u32 {
+        match self {
+            Profile::Relaxed => 50,
+            Profile::Standard => 20,
+            Profile::Strict => 10,
+        }
+    }
+
+    /// How long a flooding author stays blacklisted.
+    pub fn blacklist_duration(self) -> Duration {
+        match self {
+            Profile::Relaxed => Duration::from_secs(10 * 60),
+            Profile::Standard => Duration::from_secs(60 * 60),
+            Profile::Strict => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// The spam protection of a node: a profile, with its limits possibly overridden.
+#[derive(Debug, Clone, Copy)]
+pub struct SpamProtection {
+    pub profile: Profile,
+    pub rate_limit: RateLimit,
+    pub flood_limit: u32,
+}
+
+impl From<Profile> for SpamProtection {
+    fn from(profile: Profile) -> Self {
+        Self {
+            profile,
+            rate_limit: profile.rate_limit(),
+            flood_limit: profile.flood_limit(),
+        }
+    }
+}
+
+impl Default for SpamProtection {
+    fn default() -> Self {
+        Profile::default().into()
+    }
+}
+
+/// Subscribes to `topic` and scores the peers in it by `profile`.
+pub fn subscribe(
+    gossipsub: &mut gossipsub::Behaviour,
+    topic: &gossipsub::IdentTopic,
+    profile: Profile,
+) -> Result<bool, gossipsub::SubscriptionError> {
+    let subscribed = gossipsub.subscribe(topic)?;
+    if let Err(e) = gossipsub.set_topic_params(topic.clone(), profile.topic_params()) {
+        tracing::warn!(%topic, "Failed to set topic score parameters: {e}");
+    }
+    Ok(subscribed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILES: [Profile; 3] = [Profile::Relaxed, Profile::Standard, Profile::Strict];
+
+    #[test]
+    fn profiles_are_valid() {
+        for profile in PROFILES {
+            assert_eq!(
+                profile.peer_score_params().validate(),
+                Ok(()),
+                "{profile:?}"
+            );
+            assert_eq!(profile.thresholds().validate(), Ok(()), "{profile:?}");
+            assert_eq!(profile.topic_params().validate(), Ok(()), "{profile:?}");
+        }
+    }
+
+    #[test]
+    fn stricter_profiles_cut_off_sooner() {
+        for pair in PROFILES.windows(2) {
+            let (laxer, stricter) = (pair[0], pair[1]);
+            let (lax, strict) = (laxer.thresholds(), stricter.thresholds());
+            assert!(strict.gossip_threshold > lax.gossip_threshold);
+            assert!(strict.publish_threshold > lax.publish_threshold);
+            assert!(strict.graylist_threshold > lax.graylist_threshold);
+            assert!(
+                stricter.topic_params().invalid_message_deliveries_weight
+                    < laxer.topic_params().invalid_message_deliveries_weight
+            );
+            assert!(stricter.rate_limit().messages < laxer.rate_limit().messages);
+            assert!(stricter.flood_limit() < laxer.flood_limit());
+            assert!(stricter.blacklist_duration() > laxer.blacklist_duration());
+        }
+    }
+
+    #[test]
+    fn one_invalid_message_does_not_graylist() {
+        for profile in PROFILES {
+            let score = profile.topic_params().invalid_message_deliveries_weight;
+            assert!(
+                score > profile.thresholds().graylist_threshold,
+                "{profile:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn spam_protection_follows_the_profile() {
+        let spam = SpamProtection::from(Profile::Strict);
+        assert_eq!(spam.profile, Profile::Strict);
+        assert_eq!(
+            spam.rate_limit.messages,
+            Profile::Strict.rate_limit().messages
+        );
+        assert_eq!(spam.flood_limit, Profile::Strict.flood_limit());
+        assert_eq!(SpamProtection::default().profile, Profile::Standard);
+    }
+}
@@ -0,0 +1,350 @@
+//! How a client gets ready to use a relay, as an async state machine with a timeout per phase.
+//!
+//! 1. The listeners report their addresses.
+//! 2. The relay is dialed, retried with backoff if it fails.
+//! 3. [`Phase::Identifying`]: the relay tells us our observed address and learns its own.
+//! 4. [`Phase::RedeemingInvite`]: the invite token is presented, if there is one.
+//!
+//! A phase that does not finish in time fails with a [`StartupError`], so a client whose relay is
+//! down reports that instead of hanging.
+
+use std::{error::Error, fmt, time::Duration};
+
+use futures::stream::StreamExt;
+use libp2p::{
+    identify, request_response,
+    swarm::{dial_opts::DialOpts, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+
+use crate::{
+    access::{InviteResponse, InviteToken},
+    behaviour::{Behaviour, BehaviourEvent},
+};
+
+/// Events of the shared [`Behaviour`]'s swarm.
+// The swarm of libp2p 0.52 still names the handler error in its events, and every way of
+// spelling that type is deprecated until the error goes away in the next release.
+#[allow(deprecated)]
+pub type Event = SwarmEvent<BehaviourEvent, libp2p::swarm::THandlerErr<Behaviour>>;
+
+/// A phase that waits for the relay once connected to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Identifying,
+    RedeemingInvite,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Identifying => write!(f, "exchanging addresses with the relay"),
+            Phase::RedeemingInvite => write!(f, "redeeming the invite token"),
+        }
+    }
+}
+
+/// How long each phase may take, and how often connecting to the relay is tried.
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    /// How long to collect listen addresses. Running out of time is not an error here.
+    pub listen: Duration,
+    /// How long a single attempt to connect to the relay may take.
+    pub connect: Duration,
+    pub connect_attempts: u32,
+    /// Delay before the second attempt, doubled for every further one.
+    pub retry_backoff: Duration,
+    pub identify: Duration,
+    pub invite: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            listen: Duration::from_secs(1),
+            connect: Duration::from_secs(10),
+            connect_attempts: 3,
+            retry_backoff: Duration::from_secs(2),
+            identify: Duration::from_secs(10),
+            invite: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StartupError {
+    /// Every attempt to connect to the relay failed.
+    RelayUnreachable {
+        address: Multiaddr,
+        attempts: u32,
+        error: String,
+    },
+    /// A phase did not finish in time.
+    Timeout {
+        phase: Phase,
+        after: Duration,
+    },
+    /// The relay closed the connection before we were done with it.
+    ConnectionLost {
+        phase: Phase,
+    },
+    InviteRejected(String),
+    InviteFailed(String),
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::RelayUnreachable {
+                address,
+                attempts,
+                error,
+            } => write!(
+                f,
+                "relay at {address} unreachable after {attempts} attempts: {error}"
+            ),
+            StartupError::Timeout { phase, after } => {
+                write!(f, "timed out {phase} after {}s", after.as_secs())
+            }
+            StartupError::ConnectionLost { phase } => {
+                write!(f, "relay closed the connection while {phase}")
+            }
+            StartupError::InviteRejected(reason) => {
+                write!(f, "relay rejected our invite token: {reason}")
+            }
+            StartupError::InviteFailed(error) => {
+                write!(f, "failed to redeem invite token: {error}")
+            }
+        }
+    }
+}
+
+impl Error for StartupError {}
+
+/// The relay, once it is ready to be used.
+#[derive(Debug, Clone)]
+pub struct Relay {
+    pub peer_id: PeerId,
+    /// Our address as the relay sees it.
+    pub observed_addr: Multiaddr,
+}
+
+/// Drives the swarm through the startup phases. Events a phase does not consume are handed to
+/// `on_event`.
+pub struct Startup<'a, F> {
+    swarm: &'a mut Swarm<Behaviour>,
+    timeouts: Timeouts,
+    on_event: F,
+}
+
+impl<'a, F: FnMut(Event)> Startup<'a, F> {
+    pub fn new(swarm: &'a mut Swarm<Behaviour>, timeouts: Timeouts, on_event: F) -> Self {
+        Self {
+            swarm,
+            timeouts,
+            on_event,
+        }
+    }
+
+    /// Gives the listeners time to report their addresses.
+    pub async fn wait_for_listeners(&mut self) {
+        let mut delay = futures_timer::Delay::new(self.timeouts.listen);
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => (self.on_event)(event),
+                _ = &mut delay => return,
+            }
+        }
+    }
+
+    /// Connects to the relay at `address`, learns our observed address and redeems
+    /// `invite_token`, if any.
+    pub async fn connect_relay(
+        &mut self,
+        address: &Multiaddr,
+        invite_token: Option<InviteToken>,
+    ) -> Result<Relay, StartupError> {
+        let peer_id = self.dial_relay(address).await?;
+        let observed_addr = self.identify(peer_id).await?;
+        if let Some(token) = invite_token {
+            self.redeem_invite(peer_id, token).await?;
+        }
+
+        Ok(Relay {
+            peer_id,
+            observed_addr,
+        })
+    }
+
+    /// Dials the relay until a connection succeeds or the attempts run out.
+    async fn dial_relay(&mut self, address: &Multiaddr) -> Result<PeerId, StartupError> {
+        let mut backoff = self.timeouts.retry_backoff;
+        let mut attempt = 1;
+        loop {
+            tracing::info!(%address, attempt, "Connecting to relay server");
+            let error = match self.dial_once(address).await {
+                Ok(peer_id) => return Ok(peer_id),
+                Err(error) => error,
+            };
+            if attempt >= self.timeouts.connect_attempts {
+                return Err(StartupError::RelayUnreachable {
+                    address: address.clone(),
+                    attempts: attempt,
+                    error,
+                });
+            }
+            tracing::warn!(%address, attempt, "Failed to connect to relay: {error}");
+
+            // Keep polling the swarm while waiting, so listeners and behaviours make progress.
+            let mut delay = futures_timer::Delay::new(backoff);
+            loop {
+                tokio::select! {
+                    event = self.swarm.select_next_some() => (self.on_event)(event),
+                    _ = &mut delay => break,
+                }
+            }
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    async fn dial_once(&mut self, address: &Multiaddr) -> Result<PeerId, String> {
+        let opts = DialOpts::unknown_peer_id().address(address.clone()).build();
+        let connection = opts.connection_id();
+        self.swarm.dial(opts).map_err(|e| e.to_string())?;
+
+        let mut deadline = futures_timer::Delay::new(self.timeouts.connect);
+        loop {
+            let event = tokio::select! {
+                event = self.swarm.select_next_some() => event,
+                _ = &mut deadline => {
+                    return Err(format!("timed out after {}s", self.timeouts.connect.as_secs()));
+                }
+            };
+            match event {
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    connection_id,
+                    ..
+                } if connection_id == connection => return Ok(peer_id),
+                SwarmEvent::OutgoingConnectionError {
+                    connection_id,
+                    error,
+                    ..
+                } if connection_id == connection => return Err(error.to_string()),
+                event => (self.on_event)(event),
+            }
+        }
+    }
+
+    /// Waits until the relay told us our observed address and we told it its own, which a
+    /// freshly started relay needs to learn its public address.
+    async fn identify(&mut self, relay: PeerId) -> Result<Multiaddr, StartupError> {
+        let phase = Phase::Identifying;
+        let mut deadline = futures_timer::Delay::new(self.timeouts.identify);
+        let mut observed_addr = None;
+        let mut told_relay = false;
+
+        loop {
+            if told_relay && let Some(observed_addr) = observed_addr {
+                return Ok(observed_addr);
+            }
+            match self
+                .next_event(&mut deadline, phase, self.timeouts.identify)
+                .await?
+            {
+                SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Sent {
+                    peer_id,
+                })) if peer_id == relay => {
+                    tracing::info!("Told relay its public address");
+                    told_relay = true;
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                    peer_id,
+                    info,
+                })) if peer_id == relay => {
+                    tracing::info!(address=%info.observed_addr, "Relay told us our observed address");
+                    observed_addr = Some(info.observed_addr);
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    ..
+                } if peer_id == relay => return Err(StartupError::ConnectionLost { phase }),
+                event => (self.on_event)(event),
+            }
+        }
+    }
+
+    /// Presents `token` to `relay` and waits for it to admit us.
+    async fn redeem_invite(
+        &mut self,
+        relay: PeerId,
+        token: InviteToken,
+    ) -> Result<(), StartupError> {
+        let phase = Phase::RedeemingInvite;
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .invite
+            .send_request(&relay, token);
+        let mut deadline = futures_timer::Delay::new(self.timeouts.invite);
+
+        loop {
+            match self
+                .next_event(&mut deadline, phase, self.timeouts.invite)
+                .await?
+            {
+                SwarmEvent::Behaviour(BehaviourEvent::Invite(
+                    request_response::Event::Message {
+                        message:
+                            request_response::Message::Response {
+                                request_id: id,
+                                response,
+                            },
+                        ..
+                    },
+                )) if id == request_id => {
+                    return match response {
+                        InviteResponse::Accepted => {
+                            tracing::info!(%relay, "Relay accepted our invite token");
+                            Ok(())
+                        }
+                        InviteResponse::Rejected(reason) => {
+                            Err(StartupError::InviteRejected(reason))
+                        }
+                    };
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Invite(
+                    request_response::Event::OutboundFailure {
+                        request_id: id,
+                        error,
+                        ..
+                    },
+                )) if id == request_id => {
+                    return Err(StartupError::InviteFailed(error.to_string()));
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    ..
+                } if peer_id == relay => return Err(StartupError::ConnectionLost { phase }),
+                event => (self.on_event)(event),
+            }
+        }
+    }
+
+    /// The next swarm event, or a timeout of `phase` once `deadline`, set `after` its start,
+    /// passed.
+    async fn next_event(
+        &mut self,
+        deadline: &mut futures_timer::Delay,
+        phase: Phase,
+        after: Duration,
+    ) -> Result<Event, StartupError> {
+        tokio::select! {
+            event = self.swarm.select_next_some() => Ok(event),
+            _ = deadline => Err(StartupError::Timeout { phase, after }),
+        }
+    }
+}
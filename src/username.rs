@@ -0,0 +1,524 @@
+//! Signed username records and the registry that decides who owns a name.
+//!
+//! A [`UsernameRecord`] binds a username to a peer and is signed with that peer's key, so only
+//! the peer itself can claim a name for its PeerId. Records carry a sequence number, so newer
+//! claims of the same peer replace older ones, and an expiry, after which the name is free again.
+//!
+//! Conflicting claims by different peers are resolved first-come, first-served: once the
+//! [`Registry`] holds an unexpired record for a name, only its owner can update it, and others
+//! can claim it only after the owner let it expire.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{gossipsub, identity, PeerId};
+use serde::{Deserialize, Serialize};
+
+/// Gossipsub topic username records are published on.
+pub const TOPIC: &str = "hermes/usernames/1.0.0";
+
+/// How long a record stays valid. Owners re-announce well before it runs out.
+pub const RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How far ahead of ours a peer's clock may run. Records expiring later than [`RECORD_TTL`] plus
+/// this from now are refused, so nobody can hold a name indefinitely.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60 * 60);
+
+/// Longest accepted username, in bytes.
+const MAX_USERNAME_LEN: usize = 32;
+
+/// Prefix of the signed bytes, so a record signature is never valid for anything else.
+const SIGNING_DOMAIN: &[u8] = b"hermes-username-record:";
+
+/// A username claimed by a peer, signed with its key.
+///
+/// Every value of this type carries a valid signature: records are only created by
+/// [`UsernameRecord::new`] or checked by [`UsernameRecord::decode`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsernameRecord {
+    username: String,
+    public_key: identity::PublicKey,
+    seq: u64,
+    expires_at: u64,
+    signature: Vec<u8>,
+}
+
+/// The encoding of [`UsernameRecord`] on the wire and on disk.
+#[derive(Serialize, Deserialize)]
+struct WireRecord {
+    username: String,
+    public_key: Vec<u8>,
+    seq: u64,
+    expires_at: u64,
+    signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    Malformed,
+    InvalidUsername,
+    BadSignature,
+    Expired,
+    /// The record claims to stay valid for longer than [`RECORD_TTL`].
+    ExpiresTooLate,
+    /// The record is not newer than the one we hold for its peer.
+    Stale,
+    /// Another peer holds the name.
+    Taken {
+        owner: PeerId,
+    },
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Malformed => write!(f, "malformed username record"),
+            RecordError::InvalidUsername => write!(
+                f,
+                "usernames are 1 to {MAX_USERNAME_LEN} letters, digits, '-', '_' or '.'"
+            ),
+            RecordError::BadSignature => write!(f, "record is not signed by the claimed peer"),
+            RecordError::Expired => write!(f, "record expired"),
+            RecordError::ExpiresTooLate => write!(f, "record expires too far in the future"),
+            RecordError::Stale => write!(f, "a newer record of the same peer is known"),
+            RecordError::Taken { owner } => write!(f, "username is held by {owner}"),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl UsernameRecord {
+    /// Claims `username` for the peer of `keypair`, valid for [`RECORD_TTL`].
+    ///
+    /// The sequence number is the current time in milliseconds, so records made later always
+    /// replace earlier ones without keeping a counter across restarts.
+    pub fn new(keypair: &identity::Keypair, username: &str) -> Result<Self, RecordError> {
+        validate_username(username)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("now to be after 1970");
+        let seq = now.as_millis() as u64;
+        let expires_at = (now + RECORD_TTL).as_secs();
+        let signature = keypair
+            .sign(&Self::signed_bytes(
+                username,
+                &keypair.public(),
+                seq,
+                expires_at,
+            ))
+            .expect("signing with an Ed25519 key to succeed");
+
+        Ok(Self {
+            username: username.to_string(),
+            public_key: keypair.public(),
+            seq,
+            expires_at,
+            signature,
+        })
+    }
+
+    /// Decodes a record and checks its signature and that it does not expire too late. Whether it
+    /// already expired is checked by the [`Registry`].
+    pub fn decode(bytes: &[u8]) -> Result<Self, RecordError> {
+        let wire: WireRecord = ciborium::from_reader(bytes).map_err(|_| RecordError::Malformed)?;
+        validate_username(&wire.username)?;
+        let public_key = identity::PublicKey::try_decode_protobuf(&wire.public_key)
+            .map_err(|_| RecordError::Malformed)?;
+        if !public_key.verify(
+            &Self::signed_bytes(&wire.username, &public_key, wire.seq, wire.expires_at),
+            &wire.signature,
+        ) {
+            return Err(RecordError::BadSignature);
+        }
+        let latest = unix_secs(SystemTime::now()) + (RECORD_TTL + MAX_CLOCK_SKEW).as_secs();
+        if wire.expires_at > latest {
+            return Err(RecordError::ExpiresTooLate);
+        }
+
+        Ok(Self {
+            username: wire.username,
+            public_key,
+            seq: wire.seq,
+            expires_at: wire.expires_at,
+            signature: wire.signature,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let wire = WireRecord {
+            username: self.username.clone(),
+            public_key: self.public_key.encode_protobuf(),
+            seq: self.seq,
+            expires_at: self.expires_at,
+            signature: self.signature.clone(),
+        };
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&wire, &mut bytes).expect("writing to a Vec not to fail");
+        bytes
+    }
+
+    /// Like [`UsernameRecord::new`], with any sequence number and expiry.
+    #[cfg(test)]
+    pub(crate) fn with_expiry(
+        keypair: &identity::Keypair,
+        username: &str,
+        seq: u64,
+        expires_at: u64,
+    ) -> Self {
+        let signature = keypair
+            .sign(&Self::signed_bytes(
+                username,
+                &keypair.public(),
+                seq,
+                expires_at,
+            ))
+            .unwrap();
+        Self {
+            username: username.to_string(),
+            public_key: keypair.public(),
+            seq,
+            expires_at,
+            signature,
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn peer(&self) -> PeerId {
+        self.public_key.to_peer_id()
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Unix timestamp in seconds after which the record is no longer accepted.
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at < unix_secs(now)
+    }
+
+    fn signed_bytes(
+        username: &str,
+        public_key: &identity::PublicKey,
+        seq: u64,
+        expires_at: u64,
+    ) -> Vec<u8> {
+        let public_key = public_key.encode_protobuf();
+        let mut bytes = SIGNING_DOMAIN.to_vec();
+        bytes.extend_from_slice(&(username.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(username.as_bytes());
+        bytes.extend_from_slice(&(public_key.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&public_key);
+        bytes.extend_from_slice(&seq.to_be_bytes());
+        bytes.extend_from_slice(&expires_at.to_be_bytes());
+        bytes
+    }
+}
+
+/// Seconds since the Unix epoch at `time`, 0 before it.
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn validate_username(username: &str) -> Result<(), RecordError> {
+    let valid = !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(RecordError::InvalidUsername);
+    }
+
+    Ok(())
+}
+
+/// How gossipsub should treat a record, given the outcome of adding it to the [`Registry`].
+///
+/// Conflicting and outdated claims are validly signed, so they are dropped without penalizing
+/// the peer that forwarded them.
+pub fn acceptance(result: Result<(), &RecordError>) -> gossipsub::MessageAcceptance {
+    match result {
+        Ok(()) => gossipsub::MessageAcceptance::Accept,
+        Err(RecordError::Taken { .. } | RecordError::Stale) => gossipsub::MessageAcceptance::Ignore,
+        Err(_) => gossipsub::MessageAcceptance::Reject,
+    }
+}
+
+/// Usernames known to the local node, one record per name and per peer.
+#[derive(Debug, Default)]
+pub struct Registry {
+    by_username: HashMap<String, UsernameRecord>,
+    by_peer: HashMap<PeerId, String>,
+}
+
+impl Registry {
+    /// Accepts `record` if it is the first claim of its name, or newer than the owner's last.
+    ///
+    /// Accepting a record for a new name releases the name the peer held before.
+    pub fn insert(&mut self, record: UsernameRecord, now: SystemTime) -> Result<(), RecordError> {
+        if record.is_expired(now) {
+            return Err(RecordError::Expired);
+        }
+        let peer = record.peer();
+
+        if let Some(owner) = self.get(record.username(), now).map(UsernameRecord::peer)
+            && owner != peer
+        {
+            return Err(RecordError::Taken { owner });
+        }
+        if let Some(previous) = self
+            .by_peer
+            .get(&peer)
+            .and_then(|username| self.by_username.get(username))
+            && previous.seq >= record.seq
+        {
+            return Err(RecordError::Stale);
+        }
+
+        // Release the name the peer held before and the expired claim of someone else.
+        if let Some(username) = self.by_peer.remove(&peer) {
+            self.by_username.remove(&username);
+        }
+        if let Some(expired) = self.by_username.remove(record.username()) {
+            self.by_peer.remove(&expired.peer());
+        }
+        self.by_peer.insert(peer, record.username.clone());
+        self.by_username.insert(record.username.clone(), record);
+
+        Ok(())
+    }
+
+    /// The unexpired record for `username`, if any.
+    pub fn get(&self, username: &str, now: SystemTime) -> Option<&UsernameRecord> {
+        self.by_username
+            .get(username)
+            .filter(|record| !record.is_expired(now))
+    }
+
+    /// The unexpired record of the name `peer` holds, if any.
+    pub fn get_by_peer(&self, peer: &PeerId, now: SystemTime) -> Option<&UsernameRecord> {
+        self.by_peer
+            .get(peer)
+            .and_then(|username| self.get(username, now))
+    }
+
+    /// All unexpired records.
+    pub fn records(&self, now: SystemTime) -> impl Iterator<Item = &UsernameRecord> {
+        self.by_username
+            .values()
+            .filter(move |record| !record.is_expired(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time the registry tests run at.
+    const NOW: u64 = 1_700_000_000;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn record(
+        keypair: &identity::Keypair,
+        username: &str,
+        seq: u64,
+        expires_at: u64,
+    ) -> UsernameRecord {
+        UsernameRecord::with_expiry(keypair, username, seq, expires_at)
+    }
+
+    fn wire(record: &UsernameRecord) -> WireRecord {
+        ciborium::from_reader(record.encode().as_slice()).unwrap()
+    }
+
+    fn encode(wire: &WireRecord) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(wire, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let record = UsernameRecord::new(&keypair, "alice").unwrap();
+
+        let decoded = UsernameRecord::decode(&record.encode()).unwrap();
+        assert_eq!(decoded, record);
+        assert_eq!(decoded.peer(), keypair.public().to_peer_id());
+    }
+
+    #[test]
+    fn tampered_records_are_rejected() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let record = UsernameRecord::new(&keypair, "alice").unwrap();
+        let tampered: [fn(&mut WireRecord); 4] = [
+            |wire| wire.username = "mallory".to_string(),
+            |wire| wire.seq += 1,
+            |wire| wire.expires_at -= 1,
+            |wire| {
+                let other = identity::Keypair::generate_ed25519();
+                wire.public_key = other.public().encode_protobuf();
+            },
+        ];
+        for tamper in tampered {
+            let mut wire = wire(&record);
+            tamper(&mut wire);
+            assert_eq!(
+                UsernameRecord::decode(&encode(&wire)),
+                Err(RecordError::BadSignature)
+            );
+        }
+    }
+
+    #[test]
+    fn records_expiring_too_late_are_rejected() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let latest = unix_secs(SystemTime::now()) + (RECORD_TTL + MAX_CLOCK_SKEW).as_secs();
+
+        let skewed = record(&keypair, "alice", 1, latest - 60);
+        assert!(UsernameRecord::decode(&skewed.encode()).is_ok());
+        let too_late = record(&keypair, "alice", 1, latest + 60);
+        assert_eq!(
+            UsernameRecord::decode(&too_late.encode()),
+            Err(RecordError::ExpiresTooLate)
+        );
+    }
+
+    #[test]
+    fn invalid_usernames_are_rejected() {
+        let keypair = identity::Keypair::generate_ed25519();
+        for username in ["", "two words", "ümlaut", &"a".repeat(MAX_USERNAME_LEN + 1)] {
+            assert_eq!(
+                UsernameRecord::new(&keypair, username),
+                Err(RecordError::InvalidUsername),
+                "{username:?}"
+            );
+            let record = record(&keypair, username, 1, NOW);
+            assert_eq!(
+                UsernameRecord::decode(&record.encode()),
+                Err(RecordError::InvalidUsername)
+            );
+        }
+    }
+
+    #[test]
+    fn first_claim_of_a_name_wins() {
+        let (alice, mallory) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let mut registry = Registry::default();
+
+        registry
+            .insert(record(&alice, "alice", 1, NOW + 60), at(NOW))
+            .unwrap();
+        assert_eq!(
+            registry.insert(record(&mallory, "alice", 2, NOW + 120), at(NOW)),
+            Err(RecordError::Taken {
+                owner: alice.public().to_peer_id()
+            })
+        );
+        assert_eq!(
+            registry.get("alice", at(NOW)).map(UsernameRecord::peer),
+            Some(alice.public().to_peer_id())
+        );
+    }
+
+    #[test]
+    fn only_newer_records_of_the_owner_replace_its_claim() {
+        let alice = identity::Keypair::generate_ed25519();
+        let mut registry = Registry::default();
+
+        registry
+            .insert(record(&alice, "alice", 2, NOW + 60), at(NOW))
+            .unwrap();
+        assert_eq!(
+            registry.insert(record(&alice, "alice", 1, NOW + 120), at(NOW)),
+            Err(RecordError::Stale)
+        );
+        assert_eq!(
+            registry.insert(record(&alice, "alice", 2, NOW + 120), at(NOW)),
+            Err(RecordError::Stale)
+        );
+        registry
+            .insert(record(&alice, "alice", 3, NOW + 120), at(NOW))
+            .unwrap();
+        assert_eq!(
+            registry.get("alice", at(NOW)).map(UsernameRecord::seq),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn expired_names_can_be_taken_over() {
+        let (alice, bob) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let mut registry = Registry::default();
+
+        registry
+            .insert(record(&alice, "alice", 1, NOW + 60), at(NOW))
+            .unwrap();
+        assert_eq!(
+            registry.insert(record(&bob, "alice", 1, NOW - 1), at(NOW)),
+            Err(RecordError::Expired)
+        );
+
+        let later = at(NOW + 61);
+        assert!(registry.get("alice", later).is_none());
+        registry
+            .insert(record(&bob, "alice", 1, NOW + 120), later)
+            .unwrap();
+        assert_eq!(
+            registry.get("alice", later).map(UsernameRecord::peer),
+            Some(bob.public().to_peer_id())
+        );
+        assert!(registry
+            .get_by_peer(&alice.public().to_peer_id(), later)
+            .is_none());
+    }
+
+    #[test]
+    fn renaming_releases_the_old_name() {
+        let (alice, bob) = (
+            identity::Keypair::generate_ed25519(),
+            identity::Keypair::generate_ed25519(),
+        );
+        let mut registry = Registry::default();
+
+        registry
+            .insert(record(&alice, "alice", 1, NOW + 60), at(NOW))
+            .unwrap();
+        registry
+            .insert(record(&alice, "alicia", 2, NOW + 60), at(NOW))
+            .unwrap();
+        assert!(registry.get("alice", at(NOW)).is_none());
+        assert_eq!(
+            registry
+                .get_by_peer(&alice.public().to_peer_id(), at(NOW))
+                .map(UsernameRecord::username),
+            Some("alicia")
+        );
+
+        registry
+            .insert(record(&bob, "alice", 1, NOW + 60), at(NOW))
+            .unwrap();
+        assert_eq!(registry.records(at(NOW)).count(), 2);
+    }
+}
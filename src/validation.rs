@@ -0,0 +1,440 @@
+//! Checks every chat message before it is shown or forwarded.
+//!
+//! gossipsub holds a message back until the application judged it, so a message failing any
+//! check here never reaches other peers through us. The checks run cheapest first: size, rate
+//! limit, envelope decoding, then whether the sender's username belongs to the signing peer.
+//! Username records pass the size and rate limit checks before they are decoded.
+//!
+//! Gossipsub scores down the peers forwarding messages we reject by itself. Messages over the
+//! rate limit are only ignored, so authors sending us those directly collect offences instead,
+//! which lower their application specific gossipsub score until they expire. Authors that keep
+//! exceeding their rate limit are flooding, and get blacklisted for a while.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    time::{Duration, Instant, SystemTime},
+};
+
+use libp2p::{gossipsub, PeerId};
+
+use crate::{
+    envelope::{DecodeError, Envelope},
+    username::Registry,
+};
+
+/// Largest message we accept, encoded.
+pub const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+
+/// Longest message body we accept, in bytes.
+pub const MAX_BODY_LEN: usize = 4096;
+
+/// Application specific score a peer loses per offence.
+const OFFENCE_PENALTY: f64 = -1.0;
+
+/// How long an offence counts against a peer.
+const OFFENCE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How many messages a peer may author in a period, on average.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub per: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            messages: 10,
+            per: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Why a message was not accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalid {
+    TooLarge(usize),
+    /// Not signed by its author, which strict validation should have ruled out.
+    Anonymous,
+    /// The author sent more messages than its [`RateLimit`] allows.
+    RateLimited,
+    Malformed(DecodeError),
+    /// The envelope names a room other than the topic it was published on.
+    WrongRoom(String),
+    BodyTooLong(usize),
+    /// The author claims a username that another peer holds.
+    NameTaken {
+        username: String,
+        owner: PeerId,
+    },
+    /// The author holds a different username than the one it claims.
+    WrongName {
+        claimed: String,
+        held: String,
+    },
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invalid::TooLarge(size) => write!(
+                f,
+                "message of {size} bytes exceeds {MAX_MESSAGE_SIZE} bytes"
+            ),
+            Invalid::Anonymous => write!(f, "message is not signed"),
+            Invalid::RateLimited => write!(f, "author exceeded its rate limit"),
+            Invalid::Malformed(e) => write!(f, "{e}"),
+            Invalid::WrongRoom(room) => write!(f, "envelope is for room {room}"),
+            Invalid::BodyTooLong(len) => {
+                write!(f, "body of {len} bytes exceeds {MAX_BODY_LEN} bytes")
+            }
+            Invalid::NameTaken { username, owner } => {
+                write!(f, "username {username} belongs to {owner}")
+            }
+            Invalid::WrongName { claimed, held } => {
+                write!(f, "author claims username {claimed} but holds {held}")
+            }
+        }
+    }
+}
+
+impl Error for Invalid {}
+
+impl Invalid {
+    /// How gossipsub should treat the message.
+    ///
+    /// Only messages every peer would find invalid are rejected, which penalizes the peer that
+    /// forwarded them. A message over the rate limit may be perfectly valid, and whether a
+    /// username is bound to its author depends on the records a peer has seen, so such messages
+    /// are dropped without penalty, as are envelopes of a newer version than we can read.
+    pub fn acceptance(&self) -> gossipsub::MessageAcceptance {
+        match self {
+            Invalid::RateLimited
+            | Invalid::NameTaken { .. }
+            | Invalid::WrongName { .. }
+            | Invalid::Malformed(DecodeError::UnsupportedVersion(_)) => {
+                gossipsub::MessageAcceptance::Ignore
+            }
+            _ => gossipsub::MessageAcceptance::Reject,
+        }
+    }
+}
+
+/// A message that passed validation.
+#[derive(Debug)]
+pub struct Validated {
+    pub envelope: Envelope,
+    /// Whether the author is known to hold the username it claims. False if we have not seen a
+    /// record for the name yet.
+    pub verified: bool,
+}
+
+/// Messages an author may still send right away, refilled over time.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Validates the messages of every room and keeps track of offending peers.
+#[derive(Debug)]
+pub struct Validator {
+    rate_limit: RateLimit,
+    /// Messages over the rate limit after which an author counts as flooding.
+    flood_limit: u32,
+    buckets: HashMap<PeerId, Bucket>,
+    /// When each peer offended, the oldest first.
+    offences: HashMap<PeerId, Vec<Instant>>,
+    /// When each author exceeded its rate limit, the oldest first.
+    floods: HashMap<PeerId, Vec<Instant>>,
+}
+
+impl Validator {
+    pub fn new(rate_limit: RateLimit, flood_limit: u32) -> Self {
+        Self {
+            rate_limit,
+            flood_limit,
+            buckets: HashMap::new(),
+            offences: HashMap::new(),
+            floods: HashMap::new(),
+        }
+    }
+
+    /// Checks `message`, published on the topic of `room`, against the usernames we know.
+    pub fn validate(
+        &mut self,
+        message: &gossipsub::Message,
+        room: &str,
+        usernames: &Registry,
+    ) -> Result<Validated, Invalid> {
+        let author = self.admit(message)?;
+        let envelope = Envelope::decode(&message.data).map_err(Invalid::Malformed)?;
+        if envelope.room != room {
+            return Err(Invalid::WrongRoom(envelope.room));
+        }
+        if envelope.body.len() > MAX_BODY_LEN {
+            return Err(Invalid::BodyTooLong(envelope.body.len()));
+        }
+
+        let now = SystemTime::now();
+        if let Some(owner) = usernames.get(&envelope.sender, now).map(|r| r.peer())
+            && owner != author
+        {
+            return Err(Invalid::NameTaken {
+                username: envelope.sender,
+                owner,
+            });
+        }
+        let verified = match usernames.get_by_peer(&author, now) {
+            Some(record) if record.username() != envelope.sender => {
+                return Err(Invalid::WrongName {
+                    claimed: envelope.sender,
+                    held: record.username().to_string(),
+                });
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        Ok(Validated { envelope, verified })
+    }
+
+    /// Runs the checks that apply to every gossip message, whatever its payload: its size and
+    /// the rate limit of its author. Returns the author.
+    pub fn admit(&mut self, message: &gossipsub::Message) -> Result<PeerId, Invalid> {
+        if message.data.len() > MAX_MESSAGE_SIZE {
+            return Err(Invalid::TooLarge(message.data.len()));
+        }
+        let author = message.source.ok_or(Invalid::Anonymous)?;
+        if !self.take_token(author, Instant::now()) {
+            return Err(Invalid::RateLimited);
+        }
+        Ok(author)
+    }
+
+    /// Takes one message from the bucket of `author`, if it has one left.
+    fn take_token(&mut self, author: PeerId, now: Instant) -> bool {
+        let capacity = f64::from(self.rate_limit.messages);
+        let refill = capacity / self.rate_limit.per.as_secs_f64();
+        let bucket = self.buckets.entry(author).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Records an offence of `peer` and returns its new application specific score.
+    pub fn offence(&mut self, peer: PeerId) -> f64 {
+        let offences = self.offences.entry(peer).or_default();
+        offences.push(Instant::now());
+        offences.len() as f64 * OFFENCE_PENALTY
+    }
+
+    /// Records that a message of `author` exceeded its rate limit. Returns whether the author
+    /// did so more often than the flood limit allows.
+    pub fn flooded(&mut self, author: PeerId) -> bool {
+        let floods = self.floods.entry(author).or_default();
+        floods.push(Instant::now());
+        floods.len() > self.flood_limit as usize
+    }
+
+    /// Forgets expired offences and floods, and idle rate limits. Returns the peers whose score changed,
+    /// with their new score.
+    pub fn expire(&mut self) -> Vec<(PeerId, f64)> {
+        let now = Instant::now();
+        let mut changed = Vec::new();
+        self.offences.retain(|peer, offences| {
+            let before = offences.len();
+            offences.retain(|at| now.saturating_duration_since(*at) < OFFENCE_TTL);
+            if offences.len() != before {
+                changed.push((*peer, offences.len() as f64 * OFFENCE_PENALTY));
+            }
+            !offences.is_empty()
+        });
+        self.floods.retain(|_, floods| {
+            floods.retain(|at| now.saturating_duration_since(*at) < OFFENCE_TTL);
+            !floods.is_empty()
+        });
+        let per = self.rate_limit.per;
+        self.buckets
+            .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < per);
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity;
+
+    use super::*;
+    use crate::{envelope::Kind, username::UsernameRecord};
+
+    fn message(source: PeerId, data: Vec<u8>) -> gossipsub::Message {
+        gossipsub::Message {
+            source: Some(source),
+            data,
+            sequence_number: Some(1),
+            topic: gossipsub::TopicHash::from_raw("hermes/room/general"),
+        }
+    }
+
+    fn text(sender: &str, room: &str, body: &str) -> Vec<u8> {
+        Envelope::new(Kind::Text, 1, sender, room, body.to_string()).encode()
+    }
+
+    fn validator() -> Validator {
+        Validator::new(
+            RateLimit {
+                messages: 2,
+                per: Duration::from_secs(10),
+            },
+            3,
+        )
+    }
+
+    #[test]
+    fn bucket_is_exhausted_then_refilled() {
+        let mut validator = validator();
+        let author = PeerId::random();
+        let start = Instant::now();
+
+        assert!(validator.take_token(author, start));
+        assert!(validator.take_token(author, start));
+        assert!(!validator.take_token(author, start));
+        // Two messages per ten seconds refill one token every five seconds.
+        assert!(!validator.take_token(author, start + Duration::from_secs(4)));
+        assert!(validator.take_token(author, start + Duration::from_secs(5)));
+        assert!(!validator.take_token(author, start + Duration::from_secs(5)));
+        // Never more than the capacity, however long the author was idle.
+        let later = start + Duration::from_secs(3600);
+        assert!(validator.take_token(author, later));
+        assert!(validator.take_token(author, later));
+        assert!(!validator.take_token(author, later));
+    }
+
+    #[test]
+    fn authors_have_separate_buckets() {
+        let mut validator = validator();
+        let now = Instant::now();
+        let (flooder, other) = (PeerId::random(), PeerId::random());
+
+        while validator.take_token(flooder, now) {}
+        assert!(validator.take_token(other, now));
+    }
+
+    #[test]
+    fn authors_flood_once_over_the_flood_limit() {
+        let mut validator = validator();
+        let (flooder, other) = (PeerId::random(), PeerId::random());
+
+        for _ in 0..3 {
+            assert!(!validator.flooded(flooder));
+        }
+        assert!(validator.flooded(flooder));
+        assert!(!validator.flooded(other));
+    }
+
+    #[test]
+    fn only_deterministic_failures_are_rejected() {
+        use gossipsub::MessageAcceptance::{Ignore, Reject};
+
+        let ignored = [
+            Invalid::RateLimited,
+            Invalid::NameTaken {
+                username: "alice".to_string(),
+                owner: PeerId::random(),
+            },
+            Invalid::WrongName {
+                claimed: "alice".to_string(),
+                held: "bob".to_string(),
+            },
+            Invalid::Malformed(DecodeError::UnsupportedVersion(99)),
+        ];
+        for invalid in ignored {
+            assert!(matches!(invalid.acceptance(), Ignore), "{invalid:?}");
+        }
+
+        let rejected = [
+            Invalid::TooLarge(MAX_MESSAGE_SIZE + 1),
+            Invalid::Anonymous,
+            Invalid::Malformed(DecodeError::Malformed("garbage".to_string())),
+            Invalid::WrongRoom("other".to_string()),
+            Invalid::BodyTooLong(MAX_BODY_LEN + 1),
+        ];
+        for invalid in rejected {
+            assert!(matches!(invalid.acceptance(), Reject), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn envelope_must_match_the_room() {
+        let mut validator = validator();
+        let message = message(PeerId::random(), text("alice", "other", "hi"));
+
+        assert_eq!(
+            validator
+                .validate(&message, "general", &Registry::default())
+                .unwrap_err(),
+            Invalid::WrongRoom("other".to_string())
+        );
+    }
+
+    #[test]
+    fn body_must_not_be_too_long() {
+        let mut validator = validator();
+        let body = "a".repeat(MAX_BODY_LEN + 1);
+        let message = message(PeerId::random(), text("alice", "general", &body));
+
+        assert_eq!(
+            validator
+                .validate(&message, "general", &Registry::default())
+                .unwrap_err(),
+            Invalid::BodyTooLong(MAX_BODY_LEN + 1)
+        );
+    }
+
+    #[test]
+    fn usernames_are_bound_to_their_holders() {
+        let now = SystemTime::now();
+        let alice = identity::Keypair::generate_ed25519();
+        let bob = identity::Keypair::generate_ed25519();
+        let mut usernames = Registry::default();
+        usernames
+            .insert(UsernameRecord::new(&alice, "alice").unwrap(), now)
+            .unwrap();
+        let mut validator = validator();
+        let mut validate = |author: PeerId, sender: &str| {
+            let message = message(author, text(sender, "general", "hi"));
+            validator.validate(&message, "general", &usernames)
+        };
+        let (alice, bob) = (alice.public().to_peer_id(), bob.public().to_peer_id());
+
+        assert!(validate(alice, "alice").unwrap().verified);
+        assert_eq!(
+            validate(bob, "alice").unwrap_err(),
+            Invalid::NameTaken {
+                username: "alice".to_string(),
+                owner: alice,
+            }
+        );
+        assert_eq!(
+            validate(alice, "carol").unwrap_err(),
+            Invalid::WrongName {
+                claimed: "carol".to_string(),
+                held: "alice".to_string(),
+            }
+        );
+        // Nobody holds the name yet, so the claim cannot be verified but is not refused.
+        assert!(!validate(bob, "bob").unwrap().verified);
+    }
+}